@@ -0,0 +1,148 @@
+//! Keep the listeners alive across accept errors that don't affect the listening socket itself, and pace the
+//! accepts of the HTTP listener with `--accept-rate`.
+
+use std::{
+    future::Future,
+    io,
+    time::{Duration, Instant},
+};
+
+const BACKOFF_MIN: Duration = Duration::from_millis(10);
+const BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// Call `accept` until it yields a connection or a fatal error.
+///
+/// Errors of a single pending connection (reset, aborted, ...) are retried at once, errors caused by
+/// resource exhaustion (EMFILE, ENFILE, ENOBUFS, ...) are retried after an exponential backoff so the
+/// loop doesn't spin while the condition lasts.
+pub(crate) async fn accept_retry<T, F, Fut>(mut accept: F) -> io::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let mut delay = BACKOFF_MIN;
+    loop {
+        match accept().await {
+            Ok(conn) => return Ok(conn),
+            Err(err) if is_fatal(&err) => return Err(err),
+            Err(err) if is_connection_error(&err) => log::debug!("accept error: {}", err),
+            Err(err) => {
+                log::warn!("accept error: {}, retrying in {:?}", err, delay);
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(BACKOFF_MAX);
+            }
+        }
+    }
+}
+
+/// Token bucket of `--accept-rate`. Bursts of up to one second worth of connections are accepted at once,
+/// beyond that the accepts wait for the bucket to refill, the pending connections stay in the listen backlog.
+pub(crate) struct AcceptRate {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl AcceptRate {
+    /// A limit of `rate` connections per second, 0 for no limit
+    pub(crate) fn new(rate: u32) -> Self {
+        AcceptRate {
+            rate: rate.into(),
+            tokens: rate.into(),
+            last: Instant::now(),
+        }
+    }
+
+    /// Wait for the next accept to be allowed. Cancelling the wait doesn't use a token.
+    pub(crate) async fn acquire(&mut self) {
+        if self.rate == 0.0 {
+            return;
+        }
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate).min(self.rate);
+        self.last = now;
+        if self.tokens < 1.0 {
+            let wait = Duration::from_secs_f64((1.0 - self.tokens) / self.rate);
+            log::trace!("accept rate reached, next accept in {:?}", wait);
+            tokio::time::sleep(wait).await;
+            // The bucket holds exactly the token about to be taken
+            self.tokens = 1.0;
+            self.last = now + wait;
+        }
+        self.tokens -= 1.0;
+    }
+}
+
+fn is_connection_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+    )
+}
+
+/// The listening socket itself is unusable, retrying can't help.
+fn is_fatal(err: &io::Error) -> bool {
+    #[cfg(unix)]
+    if let Some(code) = err.raw_os_error() {
+        return matches!(code, libc::EBADF | libc::EFAULT | libc::EINVAL | libc::ENOTSOCK | libc::EOPNOTSUPP);
+    }
+    err.kind() == io::ErrorKind::InvalidInput
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_accept_retry_recovers() {
+    let mut errors = vec![
+        io::Error::from_raw_os_error(libc::EMFILE),
+        io::Error::from(io::ErrorKind::ConnectionAborted),
+        io::Error::from_raw_os_error(libc::ENFILE),
+    ];
+    let mut calls = 0;
+    let conn = accept_retry(|| {
+        calls += 1;
+        let res = errors.pop().map_or(Ok(42), Err);
+        async move { res }
+    })
+    .await
+    .unwrap();
+    assert_eq!(conn, 42);
+    assert_eq!(calls, 4);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_accept_retry_fatal() {
+    let mut calls = 0;
+    let res: io::Result<()> = accept_retry(|| {
+        calls += 1;
+        async { Err(io::Error::from_raw_os_error(libc::EBADF)) }
+    })
+    .await;
+    assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::EBADF));
+    assert_eq!(calls, 1);
+}
+
+#[tokio::test]
+async fn test_accept_rate() {
+    let mut unlimited = AcceptRate::new(0);
+    let start = Instant::now();
+    for _ in 0..1000 {
+        unlimited.acquire().await;
+    }
+    let mut rate = AcceptRate::new(20);
+    for _ in 0..20 {
+        rate.acquire().await;
+    }
+    assert!(start.elapsed() < Duration::from_millis(40));
+    // The burst is spent, the next two wait for a token each
+    rate.acquire().await;
+    rate.acquire().await;
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(90), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(500), "{:?}", elapsed);
+}
@@ -2,7 +2,7 @@
 //!
 //! This is for advance controlling server behaviors in both local and proxy servers.
 //!
-//! source link https://github.com/shadowsocks/shadowsocks-rust/blob/master/crates/shadowsocks-service/src/acl/mod.rs
+//! source link <https://github.com/shadowsocks/shadowsocks-rust/blob/master/crates/shadowsocks-service/src/acl/mod.rs>
 //!
 
 use ipnet::{IpNet, Ipv4Net, Ipv6Net};
@@ -12,13 +12,14 @@ use regex::bytes::{Regex, RegexBuilder, RegexSet, RegexSetBuilder};
 pub use socks5_impl::protocol::Address;
 use std::{
     borrow::Cow,
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     fmt,
     fs::File,
     io::{self, BufRead, BufReader, Error, ErrorKind},
     net::{IpAddr, SocketAddr},
     path::{Path, PathBuf},
     str,
+    time::Duration,
 };
 
 mod sub_domains_tree;
@@ -140,6 +141,19 @@ impl Rules {
         self.rule_set.contains(host) || self.rule_tree.contains(host) || self.rule_regex.is_match(host.as_bytes())
     }
 
+    /// Find the rule that the specified ASCII host matches, in the form it is written in the ACL file
+    fn find_host_rule(&self, host: &str) -> Option<String> {
+        let host = host.trim_end_matches('.'); // FQDN, removes the last `.`
+        if self.rule_set.contains(host) {
+            return Some(format!("|{}", host));
+        }
+        if let Some(domain) = self.rule_tree.find(host) {
+            return Some(format!("||{}", domain));
+        }
+        let idx = self.rule_regex.matches(host.as_bytes()).into_iter().next()?;
+        Some(self.rule_regex.patterns()[idx].clone())
+    }
+
     /// Check if there are no rules for IP addresses
     fn is_ip_empty(&self) -> bool {
         self.ipv4.is_empty() && self.ipv6.is_empty()
@@ -184,7 +198,8 @@ impl ParsingRules {
         self.ipv6.add(rule);
     }
 
-    fn add_regex_rule(&mut self, mut rule: String) {
+    /// Add a regex rule, return the rule in the form the ACL reports it when matched
+    fn add_regex_rule(&mut self, mut rule: String) -> String {
         static TREE_SET_RULE_EQUIV: Lazy<Regex> = Lazy::new(|| {
             RegexBuilder::new(r#"^(?:(?:\((?:\?:)?\^\|\\\.\)|(?:\^\.(?:\+|\*))?\\\.)((?:[\w-]+(?:\\\.)?)+)|\^((?:[\w-]+(?:\\\.)?)+))\$?$"#)
                 .unicode(false)
@@ -196,17 +211,17 @@ impl ParsingRules {
             if let Some(tree_rule) = caps.get(1) {
                 if let Ok(tree_rule) = str::from_utf8(tree_rule.as_bytes()) {
                     let tree_rule = tree_rule.replace("\\.", ".");
-                    if self.add_tree_rule_inner(&tree_rule).is_ok() {
+                    if let Ok(tree_rule) = self.add_tree_rule_inner(&tree_rule) {
                         // log::trace!("REGEX-RULE {} => TREE-RULE {}", rule, tree_rule);
-                        return;
+                        return tree_rule;
                     }
                 }
             } else if let Some(set_rule) = caps.get(2) {
                 if let Ok(set_rule) = str::from_utf8(set_rule.as_bytes()) {
                     let set_rule = set_rule.replace("\\.", ".");
-                    if self.add_set_rule_inner(&set_rule).is_ok() {
+                    if let Ok(set_rule) = self.add_set_rule_inner(&set_rule) {
                         // log::trace!("REGEX-RULE {} => SET-RULE {}", rule, set_rule);
-                        return;
+                        return set_rule;
                     }
                 }
             }
@@ -218,30 +233,33 @@ impl ParsingRules {
 
         // Handle it as a normal REGEX
         // FIXME: If this line is not a valid regex, how can we know without actually compile it?
-        self.rules_regex.push(rule);
+        self.rules_regex.push(rule.clone());
+        rule
     }
 
     #[inline]
-    fn add_set_rule(&mut self, rule: &str) -> io::Result<()> {
+    fn add_set_rule(&mut self, rule: &str) -> io::Result<String> {
         log::trace!("SET-RULE {}", rule);
         self.add_set_rule_inner(rule)
     }
 
-    fn add_set_rule_inner(&mut self, rule: &str) -> io::Result<()> {
-        self.rules_set.insert(self.check_is_ascii(rule)?.to_ascii_lowercase());
-        Ok(())
+    fn add_set_rule_inner(&mut self, rule: &str) -> io::Result<String> {
+        let rule = self.check_is_ascii(rule)?.to_ascii_lowercase();
+        self.rules_set.insert(rule.clone());
+        Ok(format!("|{}", rule))
     }
 
     #[inline]
-    fn add_tree_rule(&mut self, rule: &str) -> io::Result<()> {
+    fn add_tree_rule(&mut self, rule: &str) -> io::Result<String> {
         log::trace!("TREE-RULE {}", rule);
         self.add_tree_rule_inner(rule)
     }
 
-    fn add_tree_rule_inner(&mut self, rule: &str) -> io::Result<()> {
+    fn add_tree_rule_inner(&mut self, rule: &str) -> io::Result<String> {
         // SubDomainsTree do lowercase conversion inside insert
-        self.rules_tree.insert(self.check_is_ascii(rule)?);
-        Ok(())
+        let rule = self.check_is_ascii(rule)?;
+        self.rules_tree.insert(rule);
+        Ok(format!("||{}", rule.to_ascii_lowercase()))
     }
 
     fn check_is_ascii<'a>(&self, str: &'a str) -> io::Result<&'a str> {
@@ -276,6 +294,406 @@ impl ParsingRules {
     }
 }
 
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+enum Section {
+    OutboundBlock,
+    Bypass,
+    Proxy,
+}
+
+/// ACL file parsing state, shared by the including and included files
+struct AclParser {
+    mode: Mode,
+    outbound_block: ParsingRules,
+    bypass: ParsingRules,
+    proxy: ParsingRules,
+    /// The host rules with a `port=` option, by section and port
+    ports: HashMap<(Section, u16), ParsingRules>,
+    section: Section,
+    /// The `port=` option of the rule being parsed
+    port: Option<u16>,
+    timeouts: HashMap<String, Duration>,
+    sources: HashMap<String, RuleSource>,
+    clients: Vec<(IpNet, bool)>,
+    /// Every host rule in the order of the files, with its section and port, for the diagnostics
+    entries: Vec<(Section, Option<u16>, String, RuleSource)>,
+    warnings: Vec<AclWarning>,
+}
+
+impl AclParser {
+    fn new() -> Self {
+        AclParser {
+            mode: Mode::BlackList,
+            outbound_block: ParsingRules::new(Section::OutboundBlock.name()),
+            bypass: ParsingRules::new(Section::Bypass.name()),
+            proxy: ParsingRules::new(Section::Proxy.name()),
+            ports: HashMap::new(),
+            section: Section::Bypass,
+            port: None,
+            timeouts: HashMap::new(),
+            sources: HashMap::new(),
+            clients: Vec::new(),
+            entries: Vec::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    fn curr(&mut self) -> &mut ParsingRules {
+        match self.section {
+            Section::OutboundBlock => &mut self.outbound_block,
+            Section::Bypass => &mut self.bypass,
+            Section::Proxy => &mut self.proxy,
+        }
+    }
+
+    /// The rules of the current section for a host rule, those of its port with a `port=` option
+    fn curr_host(&mut self) -> &mut ParsingRules {
+        let Some(port) = self.port else {
+            return self.curr();
+        };
+        let section = self.section;
+        self.ports
+            .entry((section, port))
+            .or_insert_with(|| ParsingRules::new(section.name()))
+    }
+
+    /// Parse an ACL file, `include <path>` lines are parsed in place with paths relative to the including file.
+    /// `stack` holds the files being included, to detect include cycles.
+    fn parse_file(&mut self, path: &Path, stack: &mut Vec<PathBuf>) -> io::Result<()> {
+        let canonical_path = path
+            .canonicalize()
+            .map_err(|err| Error::new(err.kind(), format!("ACL file {}: {}", path.display(), err)))?;
+        if stack.contains(&canonical_path) {
+            let cycle = stack
+                .iter()
+                .chain(std::iter::once(&canonical_path))
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(" -> ");
+            return Err(Error::new(ErrorKind::InvalidData, format!("ACL include cycle: {}", cycle)));
+        }
+        stack.push(canonical_path);
+
+        let fp = File::open(path)?;
+        let r = BufReader::new(fp);
+
+        for (index, line) in r.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            // Comments
+            if line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+
+            let source = RuleSource {
+                file: path.to_path_buf(),
+                line: index + 1,
+                text: line.to_owned(),
+            };
+
+            if !line.is_ascii() {
+                log::warn!("ACL rule {} containing non-ASCII characters, skipped", line);
+                let reason = "non-ASCII characters, skipped".to_string();
+                self.warnings.push(AclWarning::Ignored { line: source, reason });
+                continue;
+            }
+
+            if let Some(include) = line.strip_prefix("include ") {
+                let include = Path::new(include.trim());
+                let include = path.parent().map(|dir| dir.join(include)).unwrap_or_else(|| include.to_path_buf());
+                log::trace!("ACL including {:?}", include);
+                self.parse_file(&include, stack)?;
+                continue;
+            }
+
+            self.parse_line(line, source)?;
+        }
+
+        stack.pop();
+        Ok(())
+    }
+
+    /// Parse a section line or a rule with its options, written at `source`
+    fn parse_line(&mut self, line: &str, source: RuleSource) -> io::Result<()> {
+        let mut line = line;
+        let mut timeout = None;
+        let mut port = None;
+        while let Some((rule, option)) = line.rsplit_once(char::is_whitespace) {
+            let invalid = || Error::new(ErrorKind::InvalidData, format!("ACL rule {}: invalid option {}", rule, option));
+            if let Some(secs) = option.strip_prefix("timeout=") {
+                timeout = Some(Duration::from_secs(secs.parse::<u64>().map_err(|_| invalid())?));
+            } else if let Some(number) = option.strip_prefix("port=") {
+                port = Some(number.parse::<u16>().ok().filter(|port| *port != 0).ok_or_else(invalid)?);
+            } else {
+                break;
+            }
+            line = rule.trim_end();
+        }
+        self.port = port;
+        let rule = self.parse_rule(line);
+        self.port = None;
+        let rule = match (rule?, port) {
+            // Rules of different ports are different rules
+            (Some(rule), Some(port)) => Some(format!("{} port={}", rule, port)),
+            (rule, None) => rule,
+            (None, Some(_)) => {
+                log::warn!("ACL line {} is not a host rule, port ignored", source);
+                let reason = "not a host rule, port ignored".to_string();
+                self.warnings.push(AclWarning::Ignored {
+                    line: source.clone(),
+                    reason,
+                });
+                None
+            }
+        };
+        match (&rule, timeout) {
+            (Some(rule), Some(timeout)) => {
+                log::trace!("RULE {} connect timeout {:?}", rule, timeout);
+                self.timeouts.insert(rule.clone(), timeout);
+            }
+            (None, Some(_)) => {
+                log::warn!("ACL line {} is not a host rule, timeout ignored", source);
+                let reason = "not a host rule, timeout ignored".to_string();
+                self.warnings.push(AclWarning::Ignored {
+                    line: source.clone(),
+                    reason,
+                });
+            }
+            _ => {}
+        }
+        if let Some(rule) = rule {
+            // Rules written several times are reported at their first line
+            match self.sources.get(&rule) {
+                Some(first) => self.warnings.push(AclWarning::Duplicate {
+                    rule: source.clone(),
+                    first: first.clone(),
+                }),
+                None => {
+                    self.sources.insert(rule.clone(), source.clone());
+                }
+            }
+            self.entries.push((self.section, port, rule, source));
+        }
+        Ok(())
+    }
+
+    /// Parse a section line or a rule, return the rule in the form the ACL reports it when matched
+    fn parse_rule(&mut self, line: &str) -> io::Result<Option<String>> {
+        if let Some(rule) = line.strip_prefix("client ") {
+            self.parse_client_rule(rule)?;
+            return Ok(None);
+        }
+
+        if let Some(rule) = line.strip_prefix("||") {
+            return self.curr_host().add_tree_rule(rule).map(Some);
+        }
+
+        if let Some(rule) = line.strip_prefix('|') {
+            return self.curr_host().add_set_rule(rule).map(Some);
+        }
+
+        match line {
+            "[reject_all]" | "[bypass_all]" => {
+                self.mode = Mode::WhiteList;
+                log::trace!("switch to mode {:?}", self.mode);
+            }
+            "[accept_all]" | "[proxy_all]" => {
+                self.mode = Mode::BlackList;
+                log::trace!("switch to mode {:?}", self.mode);
+            }
+            "[outbound_block_list]" => {
+                self.section = Section::OutboundBlock;
+                log::trace!("loading outbound_block_list");
+            }
+            "[black_list]" | "[bypass_list]" => {
+                self.section = Section::Bypass;
+                log::trace!("loading black_list / bypass_list");
+            }
+            "[white_list]" | "[proxy_list]" => {
+                self.section = Section::Proxy;
+                log::trace!("loading white_list / proxy_list");
+            }
+            _ => {
+                match line.parse::<IpNet>() {
+                    Ok(IpNet::V4(v4)) => {
+                        self.curr().add_ipv4_rule(v4);
+                    }
+                    Ok(IpNet::V6(v6)) => {
+                        self.curr().add_ipv6_rule(v6);
+                    }
+                    Err(..) => {
+                        // Maybe it is a pure IpAddr
+                        match line.parse::<IpAddr>() {
+                            Ok(IpAddr::V4(v4)) => {
+                                self.curr().add_ipv4_rule(v4);
+                            }
+                            Ok(IpAddr::V6(v6)) => {
+                                self.curr().add_ipv6_rule(v6);
+                            }
+                            Err(..) => {
+                                return Ok(Some(self.curr_host().add_regex_rule(line.to_owned())));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl AclParser {
+    /// Parse the `<CIDR or IP> allow|deny` of a `client` rule
+    fn parse_client_rule(&mut self, rule: &str) -> io::Result<()> {
+        let invalid = || {
+            let msg = format!("ACL rule client {}: expected client <CIDR> allow|deny", rule);
+            Error::new(ErrorKind::InvalidData, msg)
+        };
+        let (net, action) = rule.trim().split_once(char::is_whitespace).ok_or_else(invalid)?;
+        let net = net
+            .parse::<IpNet>()
+            .or_else(|_| net.parse::<IpAddr>().map(IpNet::from))
+            .map_err(|_| invalid())?;
+        let allow = match action.trim() {
+            "allow" => true,
+            "deny" => false,
+            _ => return Err(invalid()),
+        };
+        log::trace!("CLIENT-RULE {} {}", net, action.trim());
+        self.clients.push((net, allow));
+        Ok(())
+    }
+}
+
+/// Where a rule is written in the ACL files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleSource {
+    /// The ACL file, as given or as included
+    pub file: PathBuf,
+    /// The line number in `file`, starting at 1
+    pub line: usize,
+    /// The line as written, without the surrounding whitespace
+    pub text: String,
+}
+
+impl fmt::Display for RuleSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file.display(), self.line, self.text)
+    }
+}
+
+/// A non-fatal issue of an ACL file, reported by [`AccessControl::validate_file`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AclWarning {
+    /// A rule written again, in the same section or another one, only `first` is reported when matched
+    Duplicate { rule: RuleSource, first: RuleSource },
+    /// A host rule that never decides, the hosts it matches are matched first by `by`
+    Unreachable { rule: RuleSource, by: RuleSource },
+    /// A regular expression that doesn't compile, the file fails to load
+    InvalidPattern { rule: RuleSource, error: String },
+    /// A line skipped or an option ignored when loading
+    Ignored { line: RuleSource, reason: String },
+}
+
+impl fmt::Display for AclWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AclWarning::Duplicate { rule, first } => {
+                write!(f, "{}: duplicate of {}:{}", rule, first.file.display(), first.line)
+            }
+            AclWarning::Unreachable { rule, by } => write!(f, "{}: unreachable, matched first by {}", rule, by),
+            AclWarning::InvalidPattern { rule, error } => write!(f, "{}: invalid pattern: {}", rule, error),
+            AclWarning::Ignored { line, reason } => write!(f, "{}: {}", line, reason),
+        }
+    }
+}
+
+impl Section {
+    /// The order in which the sections are matched, the lower first
+    fn precedence(self) -> u8 {
+        match self {
+            Section::OutboundBlock => 0,
+            Section::Proxy => 1,
+            Section::Bypass => 2,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Section::OutboundBlock => "[outbound_block_list]",
+            Section::Bypass => "[black_list] or [bypass_list]",
+            Section::Proxy => "[white_list] or [proxy_list]",
+        }
+    }
+}
+
+/// The rules hiding others from [`AccessControl::match_host`]. Of the same section only a subdomain rule under a
+/// domain rule, a `|` rule being matched before the `||` rules, across sections any host rule under a domain
+/// rule of a section matched earlier. The rules of a port are left out.
+fn unreachable_rules(entries: &[(Section, Option<u16>, String, RuleSource)]) -> Vec<AclWarning> {
+    let entries = entries
+        .iter()
+        .filter(|(_, port, ..)| port.is_none())
+        .map(|(section, _, rule, source)| (section, rule, source))
+        .collect::<Vec<_>>();
+    let mut domains: HashMap<&str, (Section, &RuleSource)> = HashMap::new();
+    for &(section, rule, source) in &entries {
+        if let Some(domain) = rule.strip_prefix("||") {
+            let entry = domains.entry(domain).or_insert((*section, source));
+            if section.precedence() < entry.0.precedence() {
+                *entry = (*section, source);
+            }
+        }
+    }
+    let mut warnings = Vec::new();
+    for &(section, rule, source) in &entries {
+        let (host, is_domain) = match rule.strip_prefix("||") {
+            Some(domain) => (domain, true),
+            None => match rule.strip_prefix('|') {
+                Some(host) => (host, false),
+                None => continue,
+            },
+        };
+        // The host itself, then its parent domains
+        let parents = std::iter::successors(Some(host), |h| h.split_once('.').map(|(_, parent)| parent));
+        for (depth, parent) in parents.enumerate() {
+            let Some((by_section, by)) = domains.get(parent) else {
+                continue;
+            };
+            let hidden = match (depth, is_domain) {
+                // The same domain in another section is a duplicate
+                (0, true) => false,
+                (0, false) => by_section.precedence() < section.precedence(),
+                (_, true) => by_section.precedence() <= section.precedence(),
+                (_, false) => by_section.precedence() < section.precedence(),
+            };
+            if hidden {
+                warnings.push(AclWarning::Unreachable {
+                    rule: source.clone(),
+                    by: (*by).clone(),
+                });
+                break;
+            }
+        }
+    }
+    warnings
+}
+
+/// Verdict of matching a host against the ACL rules
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AclVerdict {
+    /// Connect through the proxy, with the matching rule or `None` if decided by the default mode
+    Proxy(Option<String>),
+    /// Connect directly, with the matching rule or `None` if decided by the default mode
+    Direct(Option<String>),
+    /// Blocked by the matching rule in `[outbound_block_list]`
+    Deny(String),
+}
+
 /// ACL rules
 ///
 /// ## Sections
@@ -309,6 +727,9 @@ impl ParsingRules {
 ///     * `[white_list]` - Rules for allowing
 ///     * `[outbound_block_list]` - Rules for blocking outbound addresses.
 ///
+/// Lines starting with `#` or `//` are comments.
+/// An `include <path>` line parses another ACL file in place, the path is relative to the including file.
+///
 /// ## Mode
 ///
 /// Mode is the default ACL strategy for those addresses that are not in configuration file.
@@ -325,12 +746,28 @@ impl ParsingRules {
 /// - Regular Expression for matching hosts, like `(^|\.)gmail\.com$`
 /// - Domain with preceding `|` for exact matching, like `|google.com`
 /// - Domain with preceding `||` for matching with subdomains, like `||google.com`
+///
+/// A host rule may be followed by a `timeout=<seconds>` option, the connect timeout of the connections to
+/// the hosts it matches, like `||slow.example.com timeout=30`, and by a `port=<port>` option, to only match
+/// the connections to this port, like `|example.com port=443`. The rules with a `port=` option of a section are
+/// matched before the others of the section, the rules without one match all ports.
+///
+/// ## Clients
+///
+/// A `client <CIDR or IP> allow|deny` line, in any section, decides whether the clients of its addresses may
+/// connect, like `client 10.0.0.0/8 allow`. The first rule matching a client decides, a client matching none is
+/// denied if there are `allow` rules and allowed otherwise. Without `client` rules every client is allowed.
 #[derive(Debug, Clone)]
 pub struct AccessControl {
     outbound_block: Rules,
     black_list: Rules,
     white_list: Rules,
+    /// The host rules with a `port=` option, by section and port
+    ports: HashMap<(Section, u16), Rules>,
     mode: Mode,
+    timeouts: HashMap<String, Duration>,
+    sources: HashMap<String, RuleSource>,
+    clients: Vec<(IpNet, bool)>,
     file_path: PathBuf,
 }
 
@@ -339,109 +776,124 @@ impl AccessControl {
     pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<AccessControl> {
         log::trace!("ACL loading from {:?}", p.as_ref());
 
-        let file_path_ref = p.as_ref();
-        let file_path = file_path_ref.to_path_buf();
+        let file_path = p.as_ref().to_path_buf();
 
-        let fp = File::open(file_path_ref)?;
-        let r = BufReader::new(fp);
+        let mut parser = AclParser::new();
 
-        let mut mode = Mode::BlackList;
+        log::trace!("ACL parsing start from mode {:?} and black_list / bypass_list", parser.mode);
 
-        let mut outbound_block = ParsingRules::new("[outbound_block_list]");
-        let mut bypass = ParsingRules::new("[black_list] or [bypass_list]");
-        let mut proxy = ParsingRules::new("[white_list] or [proxy_list]");
-        let mut curr = &mut bypass;
+        parser.parse_file(&file_path, &mut Vec::new())?;
 
-        log::trace!("ACL parsing start from mode {:?} and black_list / bypass_list", mode);
+        Ok(AccessControl {
+            outbound_block: parser.outbound_block.into_rules()?,
+            black_list: parser.bypass.into_rules()?,
+            white_list: parser.proxy.into_rules()?,
+            ports: parser
+                .ports
+                .into_iter()
+                .map(|(key, rules)| Ok((key, rules.into_rules()?)))
+                .collect::<io::Result<_>>()?,
+            mode: parser.mode,
+            timeouts: parser.timeouts,
+            sources: parser.sources,
+            clients: parser.clients,
+            file_path,
+        })
+    }
 
-        for line in r.lines() {
-            let line = line?;
-            if line.is_empty() {
+    /// Parse an ACL file like [`AccessControl::load_from_file`] and report its non-fatal issues: duplicate and
+    /// unreachable rules, skipped lines and ignored options. The regular expressions that don't compile, which
+    /// fail the loading, are reported one by one as well. Errors are those of reading the files, include cycles
+    /// and invalid rule options.
+    pub fn validate_file<P: AsRef<Path>>(p: P) -> io::Result<Vec<AclWarning>> {
+        let mut parser = AclParser::new();
+        parser.parse_file(p.as_ref(), &mut Vec::new())?;
+        let mut warnings = parser.warnings;
+        warnings.extend(unreachable_rules(&parser.entries));
+        for (_, port, rule, source) in &parser.entries {
+            if rule.starts_with('|') {
                 continue;
             }
-
-            // Comments
-            if line.starts_with('#') {
-                continue;
+            let pattern = match port {
+                Some(port) => rule.trim_end_matches(&format!(" port={}", port)),
+                None => rule,
+            };
+            if let Err(err) = RegexBuilder::new(pattern).unicode(false).build() {
+                warnings.push(AclWarning::InvalidPattern {
+                    rule: source.clone(),
+                    error: err.to_string(),
+                });
             }
+        }
+        Ok(warnings)
+    }
 
-            let line = line.trim();
-
-            if !line.is_ascii() {
-                log::warn!("ACL rule {} containing non-ASCII characters, skipped", line);
-                continue;
-            }
+    /// Get ACL file path
+    pub fn file_path(&self) -> &Path {
+        &self.file_path
+    }
 
-            if let Some(rule) = line.strip_prefix("||") {
-                curr.add_tree_rule(rule)?;
-                continue;
-            }
+    /// Match domain name against the rules
+    ///
+    /// Return
+    /// - `AclVerdict::Deny` if `host` is in `outbound_block_list`
+    /// - `AclVerdict::Proxy` if `host` is in `white_list` or not matched in `WhiteList` mode
+    /// - `AclVerdict::Direct` if `host` is in `black_list` or not matched in `BlackList` mode
+    pub fn match_host(&self, host: &str) -> AclVerdict {
+        self.match_host_port_inner(host, None)
+    }
 
-            if let Some(rule) = line.strip_prefix('|') {
-                curr.add_set_rule(rule)?;
-                continue;
-            }
+    /// Match domain name and port against the rules, like [`AccessControl::match_host`] with the rules of `port`
+    /// as well, which are matched first in each section
+    pub fn match_host_port(&self, host: &str, port: u16) -> AclVerdict {
+        self.match_host_port_inner(host, Some(port))
+    }
 
-            match line {
-                "[reject_all]" | "[bypass_all]" => {
-                    mode = Mode::WhiteList;
-                    log::trace!("switch to mode {:?}", mode);
-                }
-                "[accept_all]" | "[proxy_all]" => {
-                    mode = Mode::BlackList;
-                    log::trace!("switch to mode {:?}", mode);
-                }
-                "[outbound_block_list]" => {
-                    curr = &mut outbound_block;
-                    log::trace!("loading outbound_block_list");
-                }
-                "[black_list]" | "[bypass_list]" => {
-                    curr = &mut bypass;
-                    log::trace!("loading black_list / bypass_list");
-                }
-                "[white_list]" | "[proxy_list]" => {
-                    curr = &mut proxy;
-                    log::trace!("loading white_list / proxy_list");
-                }
-                _ => {
-                    match line.parse::<IpNet>() {
-                        Ok(IpNet::V4(v4)) => {
-                            curr.add_ipv4_rule(v4);
-                        }
-                        Ok(IpNet::V6(v6)) => {
-                            curr.add_ipv6_rule(v6);
-                        }
-                        Err(..) => {
-                            // Maybe it is a pure IpAddr
-                            match line.parse::<IpAddr>() {
-                                Ok(IpAddr::V4(v4)) => {
-                                    curr.add_ipv4_rule(v4);
-                                }
-                                Ok(IpAddr::V6(v6)) => {
-                                    curr.add_ipv6_rule(v6);
-                                }
-                                Err(..) => {
-                                    curr.add_regex_rule(line.to_owned());
-                                }
-                            }
-                        }
-                    }
-                }
-            }
+    fn match_host_port_inner(&self, host: &str, port: Option<u16>) -> AclVerdict {
+        let host = Self::convert_to_ascii(host);
+        let find = |section: Section, rules: &Rules| {
+            let ported = port.and_then(|port| {
+                let rule = self.ports.get(&(section, port))?.find_host_rule(&host)?;
+                Some(format!("{} port={}", rule, port))
+            });
+            ported.or_else(|| rules.find_host_rule(&host))
+        };
+        if let Some(rule) = find(Section::OutboundBlock, &self.outbound_block) {
+            return AclVerdict::Deny(rule);
+        }
+        if let Some(rule) = find(Section::Proxy, &self.white_list) {
+            return AclVerdict::Proxy(Some(rule));
+        }
+        if let Some(rule) = find(Section::Bypass, &self.black_list) {
+            return AclVerdict::Direct(Some(rule));
         }
+        if self.is_default_in_proxy_list() {
+            AclVerdict::Proxy(None)
+        } else {
+            AclVerdict::Direct(None)
+        }
+    }
 
-        Ok(AccessControl {
-            outbound_block: outbound_block.into_rules()?,
-            black_list: bypass.into_rules()?,
-            white_list: proxy.into_rules()?,
-            mode,
-            file_path,
-        })
+    /// Where `rule`, as reported by [`AccessControl::match_host`], is written in the ACL files
+    pub fn rule_source(&self, rule: &str) -> Option<&RuleSource> {
+        self.sources.get(rule)
     }
 
-    /// Get ACL file path
-    pub fn file_path(&self) -> &Path {
-        &self.file_path
+    /// The connect timeout given by the `timeout=` option of the rule `host` matches, if any
+    pub fn connect_timeout(&self, host: &str) -> Option<Duration> {
+        self.verdict_timeout(self.match_host(host))
+    }
+
+    /// The connect timeout given by the `timeout=` option of the rule `host` and `port` match, if any
+    pub fn connect_timeout_port(&self, host: &str, port: u16) -> Option<Duration> {
+        self.verdict_timeout(self.match_host_port(host, port))
+    }
+
+    fn verdict_timeout(&self, verdict: AclVerdict) -> Option<Duration> {
+        match verdict {
+            AclVerdict::Proxy(Some(rule)) | AclVerdict::Direct(Some(rule)) => self.timeouts.get(&rule).copied(),
+            _ => None,
+        }
     }
 
     /// Check if domain name is in proxy_list.
@@ -451,6 +903,8 @@ impl AccessControl {
     /// - `Some(true)` if `host` is in `white_list` (should be proxied)
     /// - `Some(false)` if `host` is in `black_list` (should be bypassed)
     /// - `None` if `host` doesn't match any rules
+    ///
+    /// `outbound_block_list` is not consulted, see [`AccessControl::match_host`] for the verdict with denies.
     pub fn check_host_in_proxy_list(&self, host: &str) -> Option<bool> {
         let host = Self::convert_to_ascii(host);
         self.check_ascii_host_in_proxy_list(&host)
@@ -510,7 +964,7 @@ impl AccessControl {
 
     /// Returns the ASCII representation a domain name,
     /// if conversion fails returns original string
-    fn convert_to_ascii(host: &str) -> Cow<str> {
+    fn convert_to_ascii(host: &str) -> Cow<'_, str> {
         idna::domain_to_ascii(host).map(From::from).unwrap_or_else(|_| host.into())
     }
 
@@ -540,6 +994,19 @@ impl AccessControl {
         }
     }
 
+    /// Check if the client `ip` may connect by the `client` rules, the first rule matching it decides. A client
+    /// matching no rule is denied if there are `allow` rules, every client is allowed without `client` rules.
+    pub fn check_client_allowed(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            IpAddr::V4(_) => ip,
+        };
+        match self.clients.iter().find(|(net, _)| net.contains(&ip)) {
+            Some((_, allow)) => *allow,
+            None => !self.clients.iter().any(|(_, allow)| *allow),
+        }
+    }
+
     /// Check if client address should be blocked (for server)
     pub fn check_client_blocked(&self, addr: &SocketAddr) -> bool {
         match self.mode {
@@ -617,3 +1084,193 @@ fn test_acl() {
     assert!(!acl.check_host_in_proxy_list("example.com").unwrap_or_default());
     assert!(acl.check_host_in_proxy_list("youtube.com").unwrap_or_default());
 }
+
+#[test]
+fn test_acl_match_host() {
+    let path = std::env::temp_dir().join(format!("socks-hub-test-match-host-{}.acl", std::process::id()));
+    std::fs::write(
+        &path,
+        "[proxy_all]\n[bypass_list]\n||cn\n|bing.com\n[proxy_list]\n(^|\\.)google\\.com$\n|x.ads.example.com\n[outbound_block_list]\n||ads.example.com\n",
+    )
+    .unwrap();
+    let acl = AccessControl::load_from_file(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(acl.match_host("www.baidu.cn"), AclVerdict::Direct(Some("||cn".into())));
+    assert_eq!(acl.match_host("bing.com"), AclVerdict::Direct(Some("|bing.com".into())));
+    assert_eq!(acl.match_host("www.bing.com"), AclVerdict::Proxy(None));
+    assert_eq!(acl.match_host("mail.google.com"), AclVerdict::Proxy(Some("||google.com".into())));
+    assert_eq!(acl.match_host("x.ads.example.com"), AclVerdict::Deny("||ads.example.com".into()));
+
+    assert_eq!(acl.check_host_in_proxy_list("www.baidu.cn"), Some(false));
+    assert_eq!(acl.check_host_in_proxy_list("mail.google.com"), Some(true));
+    assert_eq!(acl.check_host_in_proxy_list("www.bing.com"), None);
+    // The blocked hosts keep the answer of their proxy or bypass rule
+    assert_eq!(acl.check_host_in_proxy_list("x.ads.example.com"), Some(true));
+}
+
+#[test]
+fn test_acl_include() {
+    let dir = std::env::temp_dir().join(format!("socks-hub-test-include-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    std::fs::write(
+        dir.join("main.acl"),
+        "# comment\n// comment\n[proxy_all]\ninclude sub/bypass.acl\n|example.com\n",
+    )
+    .unwrap();
+    std::fs::write(dir.join("sub/bypass.acl"), "[bypass_list]\n||cn\n").unwrap();
+    std::fs::write(dir.join("a.acl"), "include b.acl\n").unwrap();
+    std::fs::write(dir.join("b.acl"), "include a.acl\n").unwrap();
+
+    let acl = AccessControl::load_from_file(dir.join("main.acl")).unwrap();
+    let cycle = AccessControl::load_from_file(dir.join("a.acl")).unwrap_err();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(acl.match_host("www.baidu.cn"), AclVerdict::Direct(Some("||cn".into())));
+    // The section switched in the included file stays in effect after the include line
+    assert_eq!(acl.match_host("example.com"), AclVerdict::Direct(Some("|example.com".into())));
+    assert_eq!(acl.match_host("google.com"), AclVerdict::Proxy(None));
+
+    assert_eq!(cycle.kind(), ErrorKind::InvalidData);
+    assert!(cycle.to_string().contains("include cycle"));
+
+    let source = acl.rule_source("||cn").unwrap();
+    assert_eq!((source.file.as_path(), source.line), (dir.join("sub/bypass.acl").as_path(), 2));
+    let source = acl.rule_source("|example.com").unwrap();
+    assert_eq!((source.line, source.text.as_str()), (5, "|example.com"));
+    assert_eq!(acl.rule_source("|google.com"), None);
+}
+
+#[test]
+fn test_acl_connect_timeout() {
+    let path = std::env::temp_dir().join(format!("socks-hub-test-timeout-{}.acl", std::process::id()));
+    std::fs::write(
+        &path,
+        "[bypass_all]\n[proxy_list]\n||slow.example.com timeout=30\n|Fast.example.com\ttimeout=1\n(^|\\.)google\\.com$ timeout=10\n||example.org\n",
+    )
+    .unwrap();
+    let acl = AccessControl::load_from_file(&path).unwrap();
+    std::fs::write(&path, "[proxy_list]\n||example.com timeout=soon\n").unwrap();
+    let invalid = AccessControl::load_from_file(&path).unwrap_err();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(
+        acl.match_host("a.slow.example.com"),
+        AclVerdict::Proxy(Some("||slow.example.com".into()))
+    );
+    assert_eq!(acl.connect_timeout("a.slow.example.com"), Some(Duration::from_secs(30)));
+    assert_eq!(acl.connect_timeout("fast.example.com"), Some(Duration::from_secs(1)));
+    assert_eq!(acl.connect_timeout("mail.google.com"), Some(Duration::from_secs(10)));
+    assert_eq!(acl.connect_timeout("www.example.org"), None);
+    assert_eq!(acl.connect_timeout("other.org"), None);
+
+    assert_eq!(invalid.kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_acl_validate_file() {
+    let path = std::env::temp_dir().join(format!("socks-hub-test-validate-{}.acl", std::process::id()));
+    std::fs::write(
+        &path,
+        "[proxy_all]\n[outbound_block_list]\n||ads.example.com\n[proxy_list]\n||example.com\n||www.example.com\n|x.ads.example.com\n\
+         [bypass_list]\n||example.com\n|mail.example.com\n|bing.com\n|bing.com\n(unclosed\n10.0.0.0/8 timeout=5\n|bücher.de\n",
+    )
+    .unwrap();
+    let warnings = AccessControl::validate_file(&path).unwrap();
+    let loaded = AccessControl::load_from_file(&path);
+    std::fs::remove_file(&path).unwrap();
+
+    let lines: Vec<(&str, usize)> = warnings
+        .iter()
+        .map(|warning| match warning {
+            AclWarning::Duplicate { rule, .. } => ("duplicate", rule.line),
+            AclWarning::Unreachable { rule, .. } => ("unreachable", rule.line),
+            AclWarning::InvalidPattern { rule, .. } => ("invalid", rule.line),
+            AclWarning::Ignored { line, .. } => ("ignored", line.line),
+        })
+        .collect();
+    assert_eq!(
+        lines,
+        [
+            ("duplicate", 9),
+            ("duplicate", 12),
+            ("ignored", 14),
+            ("ignored", 15),
+            ("unreachable", 6),
+            ("unreachable", 7),
+            ("unreachable", 10),
+            ("invalid", 13),
+        ]
+    );
+    match &warnings[0] {
+        AclWarning::Duplicate { first, .. } => assert_eq!(first.line, 5),
+        warning => panic!("{}", warning),
+    }
+    let file = path.display();
+    assert_eq!(
+        warnings[4].to_string(),
+        format!("{file}:6: ||www.example.com: unreachable, matched first by {file}:5: ||example.com")
+    );
+    assert!(loaded.is_err());
+}
+
+#[test]
+fn test_acl_client_rules() {
+    let dir = std::env::temp_dir().join(format!("socks-hub-test-clients-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("clients.acl"),
+        "[proxy_all]\nclient 10.1.0.0/16 deny\nclient 10.0.0.0/8 allow\nclient ::1 allow\n[bypass_list]\n||cn\n",
+    )
+    .unwrap();
+    std::fs::write(dir.join("deny.acl"), "client 192.0.2.1 deny\n").unwrap();
+    std::fs::write(dir.join("invalid.acl"), "client 10.0.0.0/8 maybe\n").unwrap();
+
+    let acl = AccessControl::load_from_file(dir.join("clients.acl")).unwrap();
+    let deny = AccessControl::load_from_file(dir.join("deny.acl")).unwrap();
+    let invalid = AccessControl::load_from_file(dir.join("invalid.acl")).unwrap_err();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(acl.check_client_allowed("10.2.3.4".parse().unwrap()));
+    assert!(acl.check_client_allowed("::ffff:10.2.3.4".parse().unwrap()));
+    assert!(acl.check_client_allowed("::1".parse().unwrap()));
+    assert!(!acl.check_client_allowed("10.1.2.3".parse().unwrap()));
+    assert!(!acl.check_client_allowed("192.0.2.2".parse().unwrap()));
+    // The destination rules are unaffected
+    assert_eq!(acl.match_host("www.baidu.cn"), AclVerdict::Direct(Some("||cn".into())));
+
+    assert!(!deny.check_client_allowed("192.0.2.1".parse().unwrap()));
+    assert!(deny.check_client_allowed("192.0.2.2".parse().unwrap()));
+
+    assert_eq!(invalid.kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_acl_port_rules() {
+    let path = std::env::temp_dir().join(format!("socks-hub-test-port-rules-{}.acl", std::process::id()));
+    std::fs::write(
+        &path,
+        "[bypass_all]\n[proxy_list]\n|example.com port=443 timeout=20\n[bypass_list]\n||example.com\n10.0.0.0/8 port=80\n",
+    )
+    .unwrap();
+    let acl = AccessControl::load_from_file(&path).unwrap();
+    let warnings = AccessControl::validate_file(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let rule = "|example.com port=443".to_string();
+    assert_eq!(acl.match_host_port("example.com", 443), AclVerdict::Proxy(Some(rule.clone())));
+    assert_eq!(
+        acl.match_host_port("example.com", 80),
+        AclVerdict::Direct(Some("||example.com".into()))
+    );
+    assert_eq!(acl.match_host("example.com"), AclVerdict::Direct(Some("||example.com".into())));
+    assert_eq!(acl.connect_timeout_port("example.com", 443), Some(Duration::from_secs(20)));
+    assert_eq!(acl.connect_timeout("example.com"), None);
+    assert_eq!(acl.rule_source(&rule).unwrap().line, 3);
+
+    assert!(
+        matches!(&warnings[..], [AclWarning::Ignored { line, .. }] if line.line == 6),
+        "{:?}",
+        warnings
+    );
+}
@@ -52,18 +52,26 @@ impl SubDomainsTree {
     }
 
     pub fn contains(&self, value: &str) -> bool {
+        self.find(value).is_some()
+    }
+
+    /// Find the inserted domain that `value` is equal to or a subdomain of
+    pub fn find<'a>(&self, value: &'a str) -> Option<&'a str> {
         let mut current_map = &self.0;
+        let mut matched_len = 0;
         for part in value.rsplit('.') {
             if let Some(el) = current_map.get(part) {
+                matched_len += part.len();
                 if el.included {
-                    return true;
+                    return Some(&value[value.len() - matched_len..]);
                 }
+                matched_len += 1;
                 current_map = &el.children;
             } else {
                 break;
             }
         }
-        false
+        None
     }
 
     pub fn is_empty(&self) -> bool {
@@ -0,0 +1,251 @@
+//! Admin HTTP API, enabled by the `--admin-addr` option.
+//!
+//! - `GET /connections` - number of active client connections and whether the hub is draining
+//! - `POST /drain` - stop accepting new connections, the existing ones are kept until they close
+//! - `GET /metrics` - counters and the upstream connect histogram in the Prometheus text format, or in the
+//!   OpenMetrics format with exemplars when the request accepts `application/openmetrics-text`
+//! - `GET /config` - the running config as JSON, with the passwords masked
+//! - `POST /loglevel` - change the log level to the one in the request body, `off`, `error`, ... `trace`
+//!
+//! The POST requests must authenticate with the `username` and `password` of the hub, if any, in a Basic
+//! `Authorization` header. The GET requests are not authenticated.
+
+use crate::{
+    http2socks::{empty, full, verify_basic_authorization},
+    std_io_error_other, ArgVerbosity, BoxError, Config, Credentials, TokioIo,
+};
+use bytes::Bytes;
+use http_body_util::{combinators::BoxBody, BodyExt};
+use hyper::{
+    header::{HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE},
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+use tokio::{net::TcpListener, sync::Notify};
+
+static ACTIVE_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+static DRAINING: AtomicBool = AtomicBool::new(false);
+static DRAIN_NOTIFY: Notify = Notify::const_new();
+static IDLE_NOTIFY: Notify = Notify::const_new();
+static RUNNING_CONFIG: Mutex<Option<Config>> = Mutex::new(None);
+
+/// Counts an active client connection for as long as it is alive.
+pub(crate) struct ConnectionGuard;
+
+impl ConnectionGuard {
+    pub(crate) fn new() -> Self {
+        ACTIVE_CONNECTIONS.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if ACTIVE_CONNECTIONS.fetch_sub(1, Ordering::SeqCst) == 1 {
+            IDLE_NOTIFY.notify_waiters();
+        }
+    }
+}
+
+pub(crate) fn active_connections() -> usize {
+    ACTIVE_CONNECTIONS.load(Ordering::SeqCst)
+}
+
+pub(crate) fn is_draining() -> bool {
+    DRAINING.load(Ordering::SeqCst)
+}
+
+/// Record the config of the current run, served by `GET /config`.
+pub(crate) fn set_running_config(config: &Config) {
+    *RUNNING_CONFIG.lock().unwrap() = Some(config.clone());
+}
+
+/// The config of the current run, with the settings changed at runtime applied.
+fn running_config() -> Option<Config> {
+    let mut config = RUNNING_CONFIG.lock().unwrap().clone()?;
+    config.verbosity = log::max_level().to_level().map_or(ArgVerbosity::Off, ArgVerbosity::from);
+    Some(config)
+}
+
+/// Clear the drain state left by a previous run.
+pub(crate) fn reset_draining() {
+    DRAINING.store(false, Ordering::SeqCst);
+}
+
+pub(crate) fn start_draining() {
+    DRAINING.store(true, Ordering::SeqCst);
+    DRAIN_NOTIFY.notify_waiters();
+}
+
+/// Resolves once draining has been requested.
+pub(crate) async fn drained() {
+    loop {
+        let notified = DRAIN_NOTIFY.notified();
+        if is_draining() {
+            return;
+        }
+        notified.await;
+    }
+}
+
+/// Resolves once no client connection is active.
+pub(crate) async fn idle() {
+    loop {
+        let notified = IDLE_NOTIFY.notified();
+        if active_connections() == 0 {
+            return;
+        }
+        notified.await;
+    }
+}
+
+pub(crate) async fn run(listen_addr: SocketAddr) -> Result<(), BoxError> {
+    let listener = TcpListener::bind(listen_addr).await?;
+    log::info!("Admin API listening on http://{}", listener.local_addr()?);
+    if !listen_addr.ip().is_loopback() {
+        log::warn!(
+            "admin API on {} is reachable from other hosts, only its POST requests are authenticated",
+            listen_addr
+        );
+    }
+    loop {
+        let (stream, incoming) = crate::accept::accept_retry(|| listener.accept()).await?;
+        tokio::task::spawn(async move {
+            let io = TokioIo::new(stream);
+            if let Err(err) = hyper::server::conn::http1::Builder::new()
+                .serve_connection(io, service_fn(handle))
+                .await
+            {
+                log::debug!("admin service on incoming {} error: {}", incoming, err);
+            }
+        });
+    }
+}
+
+async fn handle(req: Request<hyper::body::Incoming>) -> Result<Response<BoxBody<Bytes, hyper::Error>>, std::io::Error> {
+    log::debug!("admin request {} {}", req.method(), req.uri());
+    let credentials = RUNNING_CONFIG
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(Config::get_credentials)
+        .unwrap_or_default();
+    if req.method() == Method::POST && !is_authorized(&credentials, req.headers().get(AUTHORIZATION)) {
+        log::warn!("admin request {} {} refused, wrong or missing credentials", req.method(), req.uri());
+        let mut resp = Response::new(empty());
+        *resp.status_mut() = StatusCode::UNAUTHORIZED;
+        resp.headers_mut()
+            .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Basic realm=\"socks-hub admin\""));
+        return Ok(resp);
+    }
+    let openmetrics = req
+        .headers()
+        .get(hyper::header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/openmetrics-text"));
+    let body = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/connections") => serde_json::json!({
+            "active": active_connections(),
+            "draining": is_draining(),
+        }),
+        (&Method::POST, "/drain") => {
+            log::info!("drain requested from admin API");
+            start_draining();
+            serde_json::json!({
+                "active": active_connections(),
+                "draining": true,
+            })
+        }
+        (&Method::GET, "/config") => match running_config() {
+            Some(config) => serde_json::to_value(config.redacted()).map_err(std_io_error_other)?,
+            None => serde_json::Value::Null,
+        },
+        (&Method::POST, "/loglevel") => {
+            let body = req.into_body().collect().await.map_err(std_io_error_other)?.to_bytes();
+            let level = String::from_utf8_lossy(&body);
+            match <ArgVerbosity as clap::ValueEnum>::from_str(level.trim(), true) {
+                Ok(verbosity) => {
+                    crate::set_verbosity(verbosity);
+                    serde_json::json!({ "level": verbosity.to_string() })
+                }
+                Err(_) => {
+                    let mut resp = Response::new(full(format!("invalid log level \"{}\"\n", level.trim())));
+                    *resp.status_mut() = StatusCode::BAD_REQUEST;
+                    return Ok(resp);
+                }
+            }
+        }
+        (&Method::GET, "/metrics") => {
+            let mut resp = Response::new(full(metrics(openmetrics)));
+            let content_type = match openmetrics {
+                true => "application/openmetrics-text; version=1.0.0; charset=utf-8",
+                false => "text/plain; version=0.0.4",
+            };
+            resp.headers_mut()
+                .insert(hyper::header::CONTENT_TYPE, hyper::header::HeaderValue::from_static(content_type));
+            return Ok(resp);
+        }
+        _ => {
+            let mut resp = Response::new(empty());
+            *resp.status_mut() = StatusCode::NOT_FOUND;
+            return Ok(resp);
+        }
+    };
+    let mut resp = Response::new(full(body.to_string()));
+    resp.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("application/json"),
+    );
+    Ok(resp)
+}
+
+/// Whether the `Authorization` header `value` carries `credentials`, always true for a hub without credentials
+fn is_authorized(credentials: &Credentials, value: Option<&HeaderValue>) -> bool {
+    credentials.is_empty() || verify_basic_authorization(credentials, value)
+}
+
+/// The metrics in the Prometheus text format, or in the OpenMetrics one which names the counter families without
+/// their `_total` suffix and ends with `# EOF`
+fn metrics(openmetrics: bool) -> String {
+    let (proxied, direct, denied) = crate::routing::decision_counts();
+    let mut out = String::new();
+    out.push_str("# HELP socks_hub_active_connections Client connections currently open.\n");
+    out.push_str("# TYPE socks_hub_active_connections gauge\n");
+    out.push_str(&format!("socks_hub_active_connections {}\n", active_connections()));
+    out.push_str("# HELP socks_hub_routing_decisions_total Destinations by routing decision.\n");
+    match openmetrics {
+        true => out.push_str("# TYPE socks_hub_routing_decisions counter\n"),
+        false => out.push_str("# TYPE socks_hub_routing_decisions_total counter\n"),
+    }
+    for (decision, count) in [("proxy", proxied), ("direct", direct), ("deny", denied)] {
+        out.push_str(&format!(
+            "socks_hub_routing_decisions_total{{decision=\"{}\"}} {}\n",
+            decision, count
+        ));
+    }
+    crate::latency::write_metrics(&mut out, openmetrics);
+    if openmetrics {
+        out.push_str("# EOF\n");
+    }
+    out
+}
+
+#[test]
+fn test_is_authorized() {
+    let credentials = Credentials::new("admin", "secret");
+    // admin:secret
+    let good = HeaderValue::from_static("Basic YWRtaW46c2VjcmV0");
+    let wrong = HeaderValue::from_static("Basic YWRtaW46d3Jvbmc=");
+    assert!(is_authorized(&credentials, Some(&good)));
+    assert!(!is_authorized(&credentials, Some(&wrong)));
+    assert!(!is_authorized(&credentials, None));
+    assert!(is_authorized(&Credentials::default(), None));
+    assert!(is_authorized(&Credentials::default(), Some(&wrong)));
+}
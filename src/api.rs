@@ -1,29 +1,55 @@
 use crate::Config;
-use std::{net::SocketAddr, os::raw::c_int, sync::Arc};
+use std::{
+    net::SocketAddr,
+    os::raw::c_int,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
 
-static mut TUN_QUIT: Option<Arc<tokio::sync::mpsc::Sender<()>>> = None;
+static TUN_QUIT: Mutex<Option<Arc<tokio::sync::mpsc::Sender<()>>>> = Mutex::new(None);
+static WORKER_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of worker threads of the runtime built by `api_internal_run`, 0 for the tokio default of one per core
+pub(crate) fn set_worker_threads(worker_threads: usize) {
+    WORKER_THREADS.store(worker_threads, Ordering::Relaxed);
+}
 
 pub(crate) fn api_internal_run<F>(config: Config, callback: Option<F>) -> c_int
 where
     F: FnOnce(SocketAddr) + Send + Sync + 'static,
 {
-    if unsafe { TUN_QUIT.is_some() } {
+    if TUN_QUIT.lock().unwrap().is_some() {
         log::error!("socks-hub already started");
         return -1;
     }
 
+    let current_thread = config.current_thread;
     let block = async move {
         log::info!("config: {}", serde_json::to_string_pretty(&config)?);
 
         let (tx, quit) = tokio::sync::mpsc::channel::<()>(1);
 
-        unsafe { TUN_QUIT = Some(Arc::new(tx)) };
+        *TUN_QUIT.lock().unwrap() = Some(Arc::new(tx));
 
         crate::main_entry(&config, quit, callback).await?;
         Ok::<_, crate::BoxError>(())
     };
 
-    match tokio::runtime::Builder::new_multi_thread().enable_all().build() {
+    let mut builder = if current_thread {
+        tokio::runtime::Builder::new_current_thread()
+    } else {
+        tokio::runtime::Builder::new_multi_thread()
+    };
+    match WORKER_THREADS.load(Ordering::Relaxed) {
+        0 => {}
+        _ if current_thread => {}
+        worker_threads => {
+            builder.worker_threads(worker_threads);
+        }
+    }
+    match builder.enable_all().build() {
         Err(_err) => {
             log::error!("failed to create tokio runtime with error: {:?}", _err);
             -1
@@ -39,7 +65,8 @@ where
 }
 
 pub(crate) fn api_internal_stop() -> c_int {
-    let res = match unsafe { TUN_QUIT.take() } {
+    let tun_quit = TUN_QUIT.lock().unwrap().take();
+    let res = match tun_quit {
         None => {
             log::error!("socks-hub not started");
             -1
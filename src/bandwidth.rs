@@ -0,0 +1,180 @@
+//! The `--total-rate` cap on the bytes relayed by all the TCP connections together.
+//!
+//! A single token bucket holds up to one second worth of bytes. The relays read at most a quantum at a time
+//! and pay for what they read afterwards, so an idle connection holds no tokens. Once the bucket is empty each
+//! read waits until the bytes read by all the relays are back under the rate, so a busy connection can't
+//! starve the others.
+
+use crate::Config;
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+static TOTAL_RATE: Mutex<Option<Arc<Limiter>>> = Mutex::new(None);
+
+/// Largest number of bytes taken from the bucket at a time
+const QUANTUM_MAX: f64 = 16384.0;
+
+pub(crate) fn init(config: &Config) {
+    let limiter = (config.total_rate > 0).then(|| Arc::new(Limiter::new(config.total_rate)));
+    *TOTAL_RATE.lock().unwrap() = limiter;
+}
+
+/// The limiter of the running hub, `None` without `--total-rate`
+pub(crate) fn limiter() -> Option<Arc<Limiter>> {
+    TOTAL_RATE.lock().unwrap().clone()
+}
+
+pub(crate) struct Limiter {
+    /// Bytes per second, also the capacity of the bucket
+    rate: f64,
+    quantum: usize,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    /// Negative while the reads made so far are over the rate
+    tokens: f64,
+    last: Instant,
+}
+
+impl Limiter {
+    fn new(rate: u64) -> Self {
+        let rate = rate as f64;
+        // About ten reads a second at the full rate, but not so small that the relays spin on tiny reads
+        let quantum = (rate / 10.0).clamp(1024.0, QUANTUM_MAX).min(rate);
+        Limiter {
+            rate,
+            quantum: quantum as usize,
+            bucket: Mutex::new(Bucket {
+                tokens: rate,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take the `bytes` just read from the bucket and return how long the reader waits before its next read,
+    /// until the bytes read by all the relays are back under the rate
+    fn charge(&self, bytes: usize) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.last).as_secs_f64() * self.rate).min(self.rate);
+        bucket.last = now;
+        bucket.tokens -= bytes as f64;
+        if bucket.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(-bucket.tokens / self.rate)
+    }
+}
+
+/// A stream whose reads are charged to the bucket of a [`Limiter`] once they return data, writes are left as
+/// they are. An idle stream holds no tokens.
+pub(crate) struct Throttled<S> {
+    inner: S,
+    limiter: Arc<Limiter>,
+    /// The wait before the next read, for the bytes of the previous ones over the rate
+    delay: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl<S> Throttled<S> {
+    pub(crate) fn new(inner: S, limiter: Arc<Limiter>) -> Self {
+        Throttled {
+            inner,
+            limiter,
+            delay: None,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        if let Some(delay) = &mut this.delay {
+            ready!(delay.as_mut().poll(cx));
+            this.delay = None;
+        }
+        let len = this.limiter.quantum.min(buf.remaining());
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(len));
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        let read = limited.filled().len();
+        buf.advance(read);
+        if read > 0 {
+            let wait = this.limiter.charge(read);
+            if !wait.is_zero() {
+                log::trace!("total rate reached, waiting {:?} before the next read", wait);
+                this.delay = Some(Box::pin(tokio::time::sleep(wait)));
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[tokio::test]
+async fn test_total_rate() {
+    async fn read_at_least<S: AsyncRead + Unpin>(stream: &mut S, bytes: usize) {
+        use tokio::io::AsyncReadExt;
+        let mut buf = vec![0; 4096];
+        let mut total = 0;
+        while total < bytes {
+            total += stream.read(&mut buf).await.unwrap();
+        }
+    }
+    let limiter = Arc::new(Limiter::new(20000));
+    let mut a = Throttled::new(tokio::io::repeat(0), limiter.clone());
+    let mut b = Throttled::new(tokio::io::repeat(0), limiter);
+    let start = Instant::now();
+    // The burst of one second worth of bytes
+    read_at_least(&mut a, 20000).await;
+    assert!(start.elapsed() < Duration::from_millis(100));
+    // Both streams share the rate from then on, the last quantum each one reads is paid after the fact
+    tokio::join!(read_at_least(&mut a, 10000), read_at_least(&mut b, 10000));
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(700), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(1300), "{:?}", elapsed);
+}
+
+#[tokio::test]
+async fn test_idle_streams() {
+    use tokio::io::AsyncReadExt;
+    let limiter = Arc::new(Limiter::new(20000));
+    let mut idle = Vec::new();
+    for _ in 0..100 {
+        let (stream, peer) = tokio::io::duplex(64);
+        let mut stream = Throttled::new(stream, limiter.clone());
+        idle.push(peer);
+        tokio::spawn(async move { stream.read(&mut [0; 4096]).await });
+    }
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    // The idle streams wait for data without holding tokens, the active one gets the whole burst
+    let mut active = Throttled::new(tokio::io::repeat(0), limiter);
+    let start = Instant::now();
+    let mut total = 0;
+    while total < 20000 {
+        total += active.read(&mut [0; 4096]).await.unwrap();
+    }
+    assert!(start.elapsed() < Duration::from_millis(100), "{:?}", start.elapsed());
+}
@@ -6,30 +6,165 @@
 //! 3. send requests
 //!    $ curl -i https://www.google.com/
 
-use socks_hub::{main_entry, BoxError, Config};
-use std::net::SocketAddr;
+use socks_hub::{main_entry, BoxError, Config, FileLogger, LogColor, MultiLogger, ProxyType};
+use std::{net::SocketAddr, path::Path};
 
-#[tokio::main]
-async fn main() -> Result<(), BoxError> {
+fn main() -> Result<(), BoxError> {
     let config = Config::parse_args();
+    let mut builder = if config.current_thread {
+        tokio::runtime::Builder::new_current_thread()
+    } else {
+        tokio::runtime::Builder::new_multi_thread()
+    };
+    builder.enable_all().build()?.block_on(run(config))
+}
 
+async fn run(config: Config) -> Result<(), BoxError> {
     dotenvy::dotenv().ok();
     // let level = format!("{}={:?}", module_path!(), config.verbosity);
-    let level = config.verbosity.to_string();
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(level)).init();
+    // Without RUST_LOG, let everything through env_logger and filter on `log::max_level` instead,
+    // so that the level can be changed at runtime from the admin API
+    let rust_log = std::env::var_os(env_logger::DEFAULT_FILTER_ENV).is_some();
+    let mut builder = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("trace"));
+    // `auto` leaves the choice to the terminal detection and the RUST_LOG_STYLE variable
+    if config.log_color != LogColor::Auto {
+        builder.write_style(config.log_color.into());
+    }
+    let console = builder.build();
+    let level = if rust_log { console.filter() } else { config.verbosity.into() };
+    let mut logger = MultiLogger::new().sink(console);
+    if let Some(log_file) = &config.log_file {
+        let file = FileLogger::new(log_file).map_err(|err| format!("failed to open log file {:?}: {}", log_file, err))?;
+        logger = logger.sink(file);
+    }
+    logger.init(level)?;
+
+    log::info!("config: {}", serde_json::to_string_pretty(&config.redacted())?);
 
-    log::info!("config: {}", serde_json::to_string_pretty(&config)?);
+    if let Some(dst) = &config.test_upstream {
+        match socks_hub::test_upstream(&config, dst).await {
+            Ok(elapsed) => println!("{} reachable via socks5://{} in {:?}", dst, config.server_addr, elapsed),
+            Err(err) => {
+                eprintln!("{} unreachable via socks5://{}: {}", dst, config.server_addr, err);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
 
     let (tx, quit) = tokio::sync::mpsc::channel::<()>(1);
-    ctrlc2::set_async_handler(async move {
-        tx.send(()).await.unwrap();
-    })
-    .await;
+    let ctrlc = ctrlc2::AsyncCtrlC::new(move || true)?;
+    tokio::spawn(async move {
+        ctrlc.await?;
+        tx.send(()).await?;
+        Ok::<_, BoxError>(())
+    });
 
+    if let Some(pid_file) = &config.pid_file {
+        check_pid_file(pid_file, config.pid_file_overwrite)?;
+    }
+
+    let pid_file = config.pid_file.clone();
+    let ready_file = config.ready_file.clone();
+    let mut listener = config.clone();
+    if config.dual_listen.is_some() {
+        // The address of the HTTP listener is reported
+        listener.source_type(ProxyType::Http);
+    }
     let cb = move |addr: SocketAddr| {
-        log::info!("Listening on {}://{}", config.source_type, addr);
+        log::info!("Listening on {}", listener.listen_url(addr));
+        if let Some(pid_file) = &pid_file {
+            if let Err(err) = std::fs::write(pid_file, format!("{}\n", std::process::id())) {
+                log::error!("failed to write PID file {:?}: {}", pid_file, err);
+            }
+        }
+        if let Some(ready_file) = &ready_file {
+            if let Err(err) = std::fs::write(ready_file, format!("{}\n", addr)) {
+                log::error!("failed to write ready file {:?}: {}", ready_file, err);
+            }
+        }
+        sd_notify_ready();
+    };
+
+    let res = main_entry(&config, quit, Some(cb)).await;
+    // The files may no longer be removable once `--user` dropped the privileges
+    for path in config.pid_file.iter().chain(&config.ready_file) {
+        match std::fs::remove_file(path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => log::warn!("failed to remove {:?}: {}", path, err),
+            _ => {}
+        }
+    }
+    res?;
+    Ok(())
+}
+
+/// Tell systemd the service is ready when started as a `Type=notify` unit, which sets `NOTIFY_SOCKET`
+#[cfg(target_os = "linux")]
+fn sd_notify_ready() {
+    use std::os::linux::net::SocketAddrExt;
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let path = std::path::PathBuf::from(path);
+    // A leading `@` names a socket in the abstract namespace
+    let addr = match path.to_str().and_then(|p| p.strip_prefix('@')) {
+        Some(name) => std::os::unix::net::SocketAddr::from_abstract_name(name),
+        None => std::os::unix::net::SocketAddr::from_pathname(&path),
     };
+    let res = addr.and_then(|addr| {
+        let socket = std::os::unix::net::UnixDatagram::unbound()?;
+        socket.send_to_addr(b"READY=1", &addr)
+    });
+    if let Err(err) = res {
+        log::warn!("failed to notify systemd on {:?}: {}", path, err);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sd_notify_ready() {}
 
-    main_entry(&config, quit, Some(cb)).await?;
+/// Refuse to start if `path` names a running process, unless `overwrite` is set. A stale PID file is replaced.
+fn check_pid_file(path: &Path, overwrite: bool) -> Result<(), BoxError> {
+    let Ok(content) = std::fs::read_to_string(path) else {
+        return Ok(());
+    };
+    match content.trim().parse::<u32>() {
+        // Our own PID in the file was left by a previous run, as the first process of a restarted container
+        Ok(pid) if pid != std::process::id() && is_process_running(pid) => {
+            if !overwrite {
+                return Err(format!(
+                    "PID file {:?} belongs to running process {}, use --pid-file-overwrite to replace it",
+                    path, pid
+                )
+                .into());
+            }
+            log::warn!("replacing PID file {:?} of running process {}", path, pid);
+        }
+        _ => log::warn!("replacing stale PID file {:?}", path),
+    }
     Ok(())
 }
+
+#[cfg(unix)]
+fn is_process_running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // Signal 0 only checks that the process exists, EPERM means it does but belongs to another user
+    unsafe { libc::kill(pid, 0) == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM) }
+}
+
+#[cfg(not(unix))]
+fn is_process_running(_pid: u32) -> bool {
+    // No cheap way to check, assume it is
+    true
+}
+
+#[test]
+fn test_own_pid_file() {
+    let path = std::env::temp_dir().join(format!("socks-hub-test-{}.pid", std::process::id()));
+    std::fs::write(&path, format!("{}\n", std::process::id())).unwrap();
+    let res = check_pid_file(&path, false);
+    std::fs::remove_file(&path).unwrap();
+    assert!(res.is_ok());
+}
@@ -0,0 +1,246 @@
+//! Circuit breaker for the remote SOCKS5 servers.
+//!
+//! After `threshold` consecutive failures an upstream is opened: connections to it fail at once for a cooldown.
+//! The first connection after the cooldown is a probe (half-open), its success closes the circuit again,
+//! its failure reopens it with a doubled cooldown, up to `MAX_COOLDOWN_FACTOR` times the configured one.
+//! A probe abandoned before its outcome is known, or still running after a cooldown, lets another one go.
+
+use crate::Config;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+const MAX_COOLDOWN_FACTOR: u32 = 32;
+
+/// The breaker of the running hub, replaced on each run
+static BREAKER: Mutex<Option<Arc<Breaker>>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+        cooldown: Duration,
+    },
+    /// A probe is in flight, until `until` at most
+    HalfOpen {
+        until: Instant,
+        cooldown: Duration,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Circuit {
+    state: State,
+}
+
+/// Apply the breaker settings of the config and forget the state of a previous run.
+pub(crate) fn init(config: &Config) {
+    let cooldown = Duration::from_secs(config.circuit_breaker_cooldown);
+    *BREAKER.lock().unwrap() = Some(Arc::new(Breaker::new(config.circuit_breaker_threshold, cooldown)));
+}
+
+fn current() -> Option<Arc<Breaker>> {
+    BREAKER.lock().unwrap().clone()
+}
+
+/// An attempt to connect `upstream`, `None` if it may not be attempted now. Its outcome is recorded with
+/// [`Attempt::record`], an attempt dropped without an outcome lets another connection probe the upstream.
+pub(crate) fn attempt(upstream: SocketAddr) -> Option<Attempt> {
+    let breaker = current();
+    if breaker.as_ref().is_some_and(|breaker| !breaker.allow(upstream, Instant::now())) {
+        return None;
+    }
+    Some(Attempt {
+        breaker,
+        upstream,
+        recorded: false,
+    })
+}
+
+pub(crate) struct Attempt {
+    breaker: Option<Arc<Breaker>>,
+    upstream: SocketAddr,
+    recorded: bool,
+}
+
+impl Attempt {
+    /// Record the outcome of the connection
+    pub(crate) fn record(mut self, success: bool) {
+        self.recorded = true;
+        if let Some(breaker) = &self.breaker {
+            breaker.record(self.upstream, success, Instant::now());
+        }
+    }
+}
+
+impl Drop for Attempt {
+    fn drop(&mut self) {
+        if let (Some(breaker), false) = (&self.breaker, self.recorded) {
+            breaker.abandon(self.upstream, Instant::now());
+        }
+    }
+}
+
+/// Whether the circuits of all the `upstreams` are open, none of them may be attempted before its cooldown
+/// or the deadline of its probe elapses. Always false without the breaker.
+pub(crate) fn all_open<I: IntoIterator<Item = SocketAddr>>(upstreams: I) -> bool {
+    current().is_some_and(|breaker| breaker.all_open(upstreams, Instant::now()))
+}
+
+/// The circuits of the upstreams, disabled with a `threshold` of 0
+struct Breaker {
+    threshold: u32,
+    cooldown: Duration,
+    circuits: Mutex<HashMap<SocketAddr, Circuit>>,
+}
+
+impl Breaker {
+    fn new(threshold: u32, cooldown: Duration) -> Self {
+        Breaker {
+            threshold,
+            cooldown,
+            circuits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.threshold > 0
+    }
+
+    fn allow(&self, upstream: SocketAddr, now: Instant) -> bool {
+        if !self.enabled() {
+            return true;
+        }
+        let mut circuits = self.circuits.lock().unwrap();
+        let Some(circuit) = circuits.get_mut(&upstream) else {
+            return true;
+        };
+        match circuit.state {
+            State::Closed { .. } => true,
+            State::Open { until, cooldown } if now >= until => {
+                log::info!("upstream {} circuit half-open, probing", upstream);
+                circuit.state = State::HalfOpen {
+                    until: now + cooldown,
+                    cooldown,
+                };
+                true
+            }
+            State::HalfOpen { until, cooldown } if now >= until => {
+                log::info!("upstream {} probe still running after {:?}, probing again", upstream, cooldown);
+                circuit.state = State::HalfOpen {
+                    until: now + cooldown,
+                    cooldown,
+                };
+                true
+            }
+            // A probe is already in flight, or the cooldown is still running
+            State::Open { .. } | State::HalfOpen { .. } => false,
+        }
+    }
+
+    fn all_open<I: IntoIterator<Item = SocketAddr>>(&self, upstreams: I, now: Instant) -> bool {
+        if !self.enabled() {
+            return false;
+        }
+        let circuits = self.circuits.lock().unwrap();
+        upstreams
+            .into_iter()
+            .all(|upstream| match circuits.get(&upstream).map(|circuit| circuit.state) {
+                // A probe in flight counts as down until its deadline, after which another one may go
+                Some(State::Open { until, .. } | State::HalfOpen { until, .. }) => now < until,
+                Some(State::Closed { .. }) | None => false,
+            })
+    }
+
+    fn record(&self, upstream: SocketAddr, success: bool, now: Instant) {
+        if !self.enabled() {
+            return;
+        }
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.entry(upstream).or_insert(Circuit {
+            state: State::Closed { failures: 0 },
+        });
+        let open = |cooldown: Duration| {
+            log::warn!("upstream {} circuit open for {:?}", upstream, cooldown);
+            State::Open {
+                until: now + cooldown,
+                cooldown,
+            }
+        };
+        circuit.state = match (circuit.state, success) {
+            (State::HalfOpen { .. }, true) => {
+                log::info!("upstream {} circuit closed", upstream);
+                State::Closed { failures: 0 }
+            }
+            (_, true) => State::Closed { failures: 0 },
+            (State::Closed { failures }, false) if failures + 1 >= self.threshold => open(self.cooldown),
+            (State::Closed { failures }, false) => State::Closed { failures: failures + 1 },
+            (State::HalfOpen { cooldown, .. }, false) => open((cooldown * 2).min(self.cooldown * MAX_COOLDOWN_FACTOR)),
+            // A connection started before the circuit opened
+            (state @ State::Open { .. }, false) => state,
+        };
+    }
+
+    /// Forget a connection to `upstream` given up before its outcome, the next one probes a half-open circuit
+    fn abandon(&self, upstream: SocketAddr, now: Instant) {
+        let mut circuits = self.circuits.lock().unwrap();
+        if let Some(circuit) = circuits.get_mut(&upstream) {
+            if let State::HalfOpen { cooldown, .. } = circuit.state {
+                log::debug!("upstream {} probe abandoned", upstream);
+                circuit.state = State::Open { until: now, cooldown };
+            }
+        }
+    }
+}
+
+#[test]
+fn test_circuit_breaker() {
+    let breaker = Breaker::new(2, Duration::from_secs(5));
+    let upstream: SocketAddr = "192.0.2.1:1080".parse().unwrap();
+    let now = Instant::now();
+    let secs = |secs: u64| now + Duration::from_secs(secs);
+
+    assert!(breaker.allow(upstream, now));
+    breaker.record(upstream, false, now);
+    assert!(breaker.allow(upstream, now));
+    breaker.record(upstream, false, now);
+    // Open after two consecutive failures
+    assert!(!breaker.allow(upstream, now));
+    assert!(breaker.all_open([upstream], now));
+    assert!(!breaker.all_open([upstream, "192.0.2.2:1080".parse().unwrap()], now));
+
+    // A single probe once the cooldown elapsed
+    assert!(breaker.allow(upstream, secs(6)));
+    assert!(!breaker.allow(upstream, secs(6)));
+    breaker.record(upstream, false, secs(6));
+    // The probe failed, the cooldown doubled
+    assert!(!breaker.allow(upstream, secs(12)));
+    assert!(breaker.allow(upstream, secs(17)));
+    assert!(breaker.all_open([upstream], secs(17)));
+    breaker.record(upstream, true, secs(17));
+    assert!(!breaker.all_open([upstream], secs(17)));
+    assert!(breaker.allow(upstream, secs(17)) && breaker.allow(upstream, secs(17)));
+
+    // An abandoned probe lets the next connection probe at once
+    breaker.record(upstream, false, now);
+    breaker.record(upstream, false, now);
+    assert!(breaker.allow(upstream, secs(5)));
+    breaker.abandon(upstream, secs(5));
+    assert!(breaker.allow(upstream, secs(5)));
+    // A probe that never ends expires after the cooldown
+    assert!(!breaker.allow(upstream, secs(9)));
+    assert!(breaker.all_open([upstream], secs(9)));
+    assert!(!breaker.all_open([upstream], secs(10)));
+    assert!(breaker.allow(upstream, secs(10)));
+
+    let disabled = Breaker::new(0, Duration::from_secs(5));
+    disabled.record(upstream, false, now);
+    assert!(disabled.allow(upstream, now));
+    assert!(!disabled.all_open([upstream], now));
+}
@@ -0,0 +1,266 @@
+//! In-memory cache of the responses to forwarded HTTP GET requests, enabled by `--cache-size`.
+//!
+//! Only `200 OK` responses with a `Content-Length` and an explicit freshness, `Cache-Control: max-age` or
+//! `s-maxage`, or an `Expires` date, are stored, for at most `--cache-ttl` seconds. Responses marked `no-store`,
+//! `no-cache` or `private`, setting cookies or varying on request headers are not stored. When the cache is full
+//! the entries closest to expiring are evicted first.
+//!
+//! The cache is shared by all the clients, so the requests carrying a `Cookie` never use it, and those carrying
+//! an `Authorization` are never answered from it and only store the responses marked `public`, `s-maxage` or
+//! `must-revalidate` (RFC 9111 section 3.5).
+
+use crate::{http2socks::full, std_io_error_other, Config};
+use bytes::Bytes;
+use http_body_util::{combinators::BoxBody, BodyExt};
+use hyper::{header, HeaderMap, Method, Request, Response, StatusCode};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+static CACHE: Mutex<Option<Cache>> = Mutex::new(None);
+
+struct Cache {
+    capacity: usize,
+    max_ttl: Duration,
+    used: usize,
+    entries: HashMap<String, Entry>,
+}
+
+struct Entry {
+    headers: HeaderMap,
+    body: Bytes,
+    stored: Instant,
+    expires: Instant,
+}
+
+pub(crate) fn init(config: &Config) {
+    *CACHE.lock().unwrap() = (config.cache_size > 0).then(|| Cache {
+        capacity: config.cache_size,
+        max_ttl: Duration::from_secs(config.cache_ttl),
+        used: 0,
+        entries: HashMap::new(),
+    });
+}
+
+/// The cache key of a request
+pub(crate) struct Key {
+    name: String,
+    /// The request carries an `Authorization`
+    authorized: bool,
+}
+
+/// The cache key of `req` if its response may come from or go to the cache
+pub(crate) fn key<B>(req: &Request<B>) -> Option<Key> {
+    if CACHE.lock().unwrap().is_none() {
+        return None;
+    }
+    request_key(req)
+}
+
+fn request_key<B>(req: &Request<B>) -> Option<Key> {
+    if req.method() != Method::GET || req.headers().contains_key(header::COOKIE) {
+        return None;
+    }
+    Some(Key {
+        name: format!("{} {}", req.method(), req.uri()),
+        authorized: req.headers().contains_key(header::AUTHORIZATION),
+    })
+}
+
+/// A fresh cached response for `key`, unless the client asks for a new one or is authenticated
+pub(crate) fn lookup<B>(key: &Key, req: &Request<B>) -> Option<Response<BoxBody<Bytes, hyper::Error>>> {
+    let directives = cache_control(req.headers());
+    if key.authorized || directives.iter().any(|d| d == "no-cache" || d == "no-store") {
+        return None;
+    }
+    let key = &key.name;
+    let mut cache = CACHE.lock().unwrap();
+    let cache = cache.as_mut()?;
+    let now = Instant::now();
+    match cache.entries.get(key) {
+        Some(entry) if entry.expires > now => {
+            log::debug!("cache hit for {}", key);
+            let mut resp = Response::new(full(entry.body.clone()));
+            *resp.headers_mut() = entry.headers.clone();
+            resp.headers_mut()
+                .insert(header::AGE, now.duration_since(entry.stored).as_secs().into());
+            Some(resp)
+        }
+        Some(_) => {
+            cache.remove(key);
+            None
+        }
+        None => None,
+    }
+}
+
+/// Store `resp` under `key` if it is cacheable, the response is handed back either way
+pub(crate) async fn store(
+    key: Key,
+    resp: Response<BoxBody<Bytes, hyper::Error>>,
+) -> std::io::Result<Response<BoxBody<Bytes, hyper::Error>>> {
+    let (capacity, max_ttl) = match CACHE.lock().unwrap().as_ref() {
+        Some(cache) => (cache.capacity, cache.max_ttl),
+        None => return Ok(resp),
+    };
+    if key.authorized && !shared_for_authorized(resp.headers()) {
+        return Ok(resp);
+    }
+    let key = key.name;
+    let Some(ttl) = freshness(resp.status(), resp.headers()).map(|ttl| ttl.min(max_ttl)) else {
+        return Ok(resp);
+    };
+    let length = resp
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse::<usize>().ok());
+    if !length.is_some_and(|length| length <= capacity) {
+        return Ok(resp);
+    }
+
+    let (parts, body) = resp.into_parts();
+    let body = body.collect().await.map_err(std_io_error_other)?.to_bytes();
+    let now = Instant::now();
+    let entry = Entry {
+        headers: parts.headers.clone(),
+        body: body.clone(),
+        stored: now,
+        expires: now + ttl,
+    };
+    if let Some(cache) = CACHE.lock().unwrap().as_mut() {
+        log::debug!("caching {} for {}s", key, ttl.as_secs());
+        cache.insert(key, entry, now);
+    }
+    Ok(Response::from_parts(parts, full(body)))
+}
+
+impl Cache {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.used -= entry.body.len();
+        }
+    }
+
+    fn insert(&mut self, key: String, entry: Entry, now: Instant) {
+        self.remove(&key);
+        let expired: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(_, e)| e.expires <= now)
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in expired {
+            self.remove(&key);
+        }
+        while self.used + entry.body.len() > self.capacity {
+            let Some(key) = self.entries.iter().min_by_key(|(_, e)| e.expires).map(|(k, _)| k.clone()) else {
+                break;
+            };
+            self.remove(&key);
+        }
+        self.used += entry.body.len();
+        self.entries.insert(key, entry);
+    }
+}
+
+/// The lowercased directives of the `Cache-Control` headers
+fn cache_control(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| directive.trim().to_ascii_lowercase())
+        .collect()
+}
+
+/// Whether a response to a request carrying an `Authorization` may be stored in a shared cache
+fn shared_for_authorized(headers: &HeaderMap) -> bool {
+    cache_control(headers)
+        .iter()
+        .any(|d| d == "public" || d == "must-revalidate" || d.starts_with("s-maxage="))
+}
+
+/// How long a response may be served from the cache, `None` if it may not be stored
+fn freshness(status: StatusCode, headers: &HeaderMap) -> Option<Duration> {
+    if status != StatusCode::OK || headers.contains_key(header::SET_COOKIE) || headers.contains_key(header::VARY) {
+        return None;
+    }
+    let directives = cache_control(headers);
+    if directives.iter().any(|d| matches!(d.as_str(), "no-store" | "no-cache" | "private")) {
+        return None;
+    }
+    let max_age = |name: &str| {
+        directives
+            .iter()
+            .find_map(|d| d.strip_prefix(name)?.strip_prefix('=')?.trim_matches('"').parse::<u64>().ok())
+    };
+    if let Some(secs) = max_age("s-maxage").or_else(|| max_age("max-age")) {
+        return (secs > 0).then(|| Duration::from_secs(secs));
+    }
+    let expires = headers.get(header::EXPIRES)?.to_str().ok()?;
+    let expires = chrono::DateTime::parse_from_rfc2822(expires).ok()?;
+    let ttl = expires.signed_duration_since(chrono::Utc::now()).to_std().ok()?;
+    (!ttl.is_zero()).then_some(ttl)
+}
+
+#[test]
+fn test_freshness() {
+    let headers = |pairs: &[(header::HeaderName, &'static str)]| {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.append(name.clone(), header::HeaderValue::from_static(value));
+        }
+        map
+    };
+    let ok = StatusCode::OK;
+    assert_eq!(freshness(ok, &headers(&[])), None);
+    assert_eq!(
+        freshness(ok, &headers(&[(header::CACHE_CONTROL, "public, max-age=60")])),
+        Some(Duration::from_secs(60))
+    );
+    assert_eq!(
+        freshness(ok, &headers(&[(header::CACHE_CONTROL, "max-age=60, s-maxage=30")])),
+        Some(Duration::from_secs(30))
+    );
+    assert_eq!(freshness(ok, &headers(&[(header::CACHE_CONTROL, "max-age=60, private")])), None);
+    assert_eq!(freshness(ok, &headers(&[(header::CACHE_CONTROL, "max-age=0")])), None);
+    assert_eq!(
+        freshness(StatusCode::NOT_FOUND, &headers(&[(header::CACHE_CONTROL, "max-age=60")])),
+        None
+    );
+    assert_eq!(
+        freshness(ok, &headers(&[(header::CACHE_CONTROL, "max-age=60"), (header::VARY, "Cookie")])),
+        None
+    );
+    assert_eq!(freshness(ok, &headers(&[(header::EXPIRES, "Thu, 01 Jan 1970 00:00:00 GMT")])), None);
+    let in_an_hour = (chrono::Utc::now() + chrono::Duration::hours(1)).to_rfc2822();
+    let mut map = HeaderMap::new();
+    map.insert(header::EXPIRES, in_an_hour.parse().unwrap());
+    assert!(freshness(ok, &map).is_some_and(|ttl| ttl > Duration::from_secs(3500)));
+}
+
+#[test]
+fn test_authorized_requests() {
+    let request = |name: &header::HeaderName| {
+        Request::get("http://cache-auth.test/")
+            .header(name, header::HeaderValue::from_static("secret"))
+            .body(())
+            .unwrap()
+    };
+    assert!(request_key(&request(&header::COOKIE)).is_none());
+    let req = request(&header::AUTHORIZATION);
+    let key = request_key(&req).unwrap();
+    assert!(key.authorized);
+    assert!(lookup(&key, &req).is_none());
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CACHE_CONTROL, header::HeaderValue::from_static("max-age=60"));
+    assert!(!shared_for_authorized(&headers));
+    headers.insert(header::CACHE_CONTROL, header::HeaderValue::from_static("max-age=60, public"));
+    assert!(shared_for_authorized(&headers));
+    headers.insert(header::CACHE_CONTROL, header::HeaderValue::from_static("s-maxage=60"));
+    assert!(shared_for_authorized(&headers));
+}
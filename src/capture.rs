@@ -0,0 +1,189 @@
+//! The `--capture-dir` debugging aid: the raw bytes relayed by each TCP connection are appended to a file of
+//! their own, named by a connection ID, up to `--capture-max-size` bytes.
+//!
+//! Each chunk read from one side is written as a header line `<milliseconds> <direction> <length>`, the time
+//! since the relay started and `c>s` from the client to the server or `s>c` the other way, then the bytes
+//! themselves and a newline. The records are buffered, a file is complete once its connection is closed. The
+//! files hold the traffic in clear, the capture is never on unless configured.
+
+use crate::Config;
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Context, Poll},
+    time::Instant,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Size of the buffer in front of each capture file, the writes to the file block the relay
+const BUFFER_SIZE: usize = 64 * 1024;
+
+static CAPTURE: Mutex<Option<(PathBuf, u64)>> = Mutex::new(None);
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+pub(crate) fn init(config: &Config) {
+    let capture = config.capture_dir.clone().map(|dir| (dir, config.capture_max_size));
+    if let Some((dir, max_size)) = &capture {
+        log::warn!(
+            "capturing the bytes of every relayed connection to {:?}, up to {} each, the files hold the traffic in clear",
+            dir,
+            crate::format_bytes(*max_size)
+        );
+    }
+    *CAPTURE.lock().unwrap() = capture;
+}
+
+/// The capture file of a new connection, `None` without `--capture-dir` or when the file can't be created
+pub(crate) fn start() -> Option<Arc<Mutex<Capture>>> {
+    let (dir, max_size) = CAPTURE.lock().unwrap().clone()?;
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let timestamp = chrono::Local::now().format("%Y%m%d-%H%M%S");
+    let path = dir.join(format!("{}-{}-{}.cap", timestamp, std::process::id(), id));
+    match Capture::create(path.clone(), max_size) {
+        Ok(capture) => {
+            log::info!("capturing connection {} to {:?}", id, path);
+            Some(Arc::new(Mutex::new(capture)))
+        }
+        Err(err) => {
+            log::warn!("failed to create capture file {:?}: {}, connection {} not captured", path, err, id);
+            None
+        }
+    }
+}
+
+pub(crate) struct Capture {
+    path: PathBuf,
+    /// `None` once the size limit is reached or a write failed
+    file: Option<BufWriter<File>>,
+    written: u64,
+    max_size: u64,
+    start: Instant,
+}
+
+impl Capture {
+    fn create(path: PathBuf, max_size: u64) -> io::Result<Self> {
+        let file = std::fs::OpenOptions::new().write(true).create_new(true).open(&path)?;
+        Ok(Capture {
+            path,
+            file: Some(BufWriter::with_capacity(BUFFER_SIZE, file)),
+            written: 0,
+            max_size,
+            start: Instant::now(),
+        })
+    }
+
+    fn record(&mut self, direction: &str, bytes: &[u8]) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        let len = bytes.len().min(self.max_size.saturating_sub(self.written) as usize);
+        let header = format!("{} {} {}\n", self.start.elapsed().as_millis(), direction, len);
+        let res = file
+            .write_all(header.as_bytes())
+            .and_then(|_| file.write_all(&bytes[..len]))
+            .and_then(|_| file.write_all(b"\n"));
+        self.written += len as u64;
+        if let Err(err) = res {
+            log::warn!("failed to write capture file {:?}: {}, capture stopped", self.path, err);
+            self.file = None;
+        } else if len < bytes.len() {
+            log::info!("capture file {:?} reached {} bytes, capture stopped", self.path, self.max_size);
+            self.stop();
+        }
+    }
+
+    /// Write out what is left in the buffer and close the file
+    fn stop(&mut self) {
+        if let Some(mut file) = self.file.take() {
+            if let Err(err) = file.flush() {
+                log::warn!("failed to write capture file {:?}: {}", self.path, err);
+            }
+        }
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// A stream whose reads are recorded in a [`Capture`], if any, writes are left as they are
+pub(crate) struct Captured<S> {
+    inner: S,
+    capture: Option<Arc<Mutex<Capture>>>,
+    /// `c>s` for the reads from the client, `s>c` for those from the server
+    direction: &'static str,
+}
+
+impl<S> Captured<S> {
+    pub(crate) fn new(inner: S, capture: Option<Arc<Mutex<Capture>>>, from_client: bool) -> Self {
+        let direction = if from_client { "c>s" } else { "s>c" };
+        Captured { inner, capture, direction }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Captured<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        let read = &buf.filled()[before..];
+        if let (Some(capture), false) = (&this.capture, read.is_empty()) {
+            capture.lock().unwrap().record(this.direction, read);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Captured<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[tokio::test]
+async fn test_capture() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let path = std::env::temp_dir().join(format!("socks-hub-test-capture-{}.cap", std::process::id()));
+    let capture = Some(Arc::new(Mutex::new(Capture::create(path.clone(), 8).unwrap())));
+    let (client, mut client_peer) = tokio::io::duplex(64);
+    let (server, mut server_peer) = tokio::io::duplex(64);
+    let mut client = Captured::new(client, capture.clone(), true);
+    let mut server = Captured::new(server, capture, false);
+    let mut buf = [0; 16];
+
+    client_peer.write_all(b"hello").await.unwrap();
+    assert_eq!(client.read(&mut buf).await.unwrap(), 5);
+    server_peer.write_all(b"world!").await.unwrap();
+    assert_eq!(server.read(&mut buf).await.unwrap(), 6);
+    // Past the size limit
+    client_peer.write_all(b"more").await.unwrap();
+    assert_eq!(client.read(&mut buf).await.unwrap(), 4);
+    // The file is complete once both sides are closed
+    drop((client, server));
+
+    let content = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let content = String::from_utf8(content).unwrap();
+    let lines: Vec<&str> = content.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert!(lines[0].ends_with(" c>s 5"), "{}", lines[0]);
+    assert_eq!(lines[1], "hello");
+    assert!(lines[2].ends_with(" s>c 3"), "{}", lines[2]);
+    assert_eq!(lines[3], "wor");
+}
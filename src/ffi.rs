@@ -4,8 +4,18 @@ use crate::{ArgVerbosity, Config, ProxyType};
 use std::{
     net::SocketAddr,
     os::raw::{c_char, c_int, c_void},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
 };
 
+static ACL_FILE: Mutex<Option<PathBuf>> = Mutex::new(None);
+static LOG_FILE: Mutex<Option<PathBuf>> = Mutex::new(None);
+static CURRENT_THREAD: AtomicBool = AtomicBool::new(false);
+static FEATURES: std::sync::OnceLock<std::ffi::CString> = std::sync::OnceLock::new();
+
 #[derive(Clone)]
 pub struct CCallback(pub Option<unsafe extern "C" fn(c_int, *mut c_void)>, pub *mut c_void);
 
@@ -38,8 +48,16 @@ pub unsafe extern "C" fn socks_hub_run(
     callback: Option<unsafe extern "C" fn(c_int, *mut c_void)>,
     ctx: *mut c_void,
 ) -> c_int {
+    let mut logger = crate::MultiLogger::new().sink(crate::dump_logger::DumpLogger::default());
+    let log_file = LOG_FILE.lock().unwrap().clone();
+    if let Some(log_file) = &log_file {
+        match crate::FileLogger::new(log_file) {
+            Ok(file) => logger = logger.sink(file),
+            Err(err) => eprintln!("failed to open log file {:?}: {}", log_file, err),
+        }
+    }
     log::set_max_level(verbosity.into());
-    if let Err(err) = log::set_boxed_logger(Box::<crate::dump_logger::DumpLogger>::default()) {
+    if let Err(err) = logger.init(verbosity.into()) {
         log::warn!("Failed to set logger: {}", err);
     }
 
@@ -64,10 +82,102 @@ pub unsafe extern "C" fn socks_hub_run(
         .verbosity(verbosity)
         .listen_addr(local_addr)
         .server_addr(server_addr);
+    if let Some(acl_file) = ACL_FILE.lock().unwrap().clone() {
+        config.acl_file(acl_file);
+    }
+    config.current_thread(CURRENT_THREAD.load(Ordering::Relaxed));
 
     crate::api::api_internal_run(config, Some(cb))
 }
 
+/// # Safety
+///
+/// Set the ACL (Access Control List) file path used by the `socks_hub_run` function.
+/// This function must be called before the `socks_hub_run` function to take effect.
+/// The `acl_file` argument is the file path, which is a string, or a null pointer which means no ACL.
+/// Return 0 on success, or -1 if the `acl_file` is not a valid UTF-8 string.
+#[no_mangle]
+pub unsafe extern "C" fn socks_hub_set_acl(acl_file: *const c_char) -> c_int {
+    let acl_file = if acl_file.is_null() {
+        None
+    } else {
+        match std::ffi::CStr::from_ptr(acl_file).to_str() {
+            Ok(acl_file) => Some(PathBuf::from(acl_file)),
+            Err(err) => {
+                log::error!("invalid ACL file path: {}", err);
+                return -1;
+            }
+        }
+    };
+    *ACL_FILE.lock().unwrap() = acl_file;
+    0
+}
+
+/// # Safety
+///
+/// Set a file the `socks_hub_run` function appends the log to, besides handing it to the log callback.
+/// This function must be called before the `socks_hub_run` function to take effect.
+/// The `log_file` argument is the file path, which is a string, or a null pointer which means no log file.
+/// Return 0 on success, or -1 if the `log_file` is not a valid UTF-8 string.
+#[no_mangle]
+pub unsafe extern "C" fn socks_hub_set_log_file(log_file: *const c_char) -> c_int {
+    let log_file = if log_file.is_null() {
+        None
+    } else {
+        match std::ffi::CStr::from_ptr(log_file).to_str() {
+            Ok(log_file) => Some(PathBuf::from(log_file)),
+            Err(err) => {
+                log::error!("invalid log file path: {}", err);
+                return -1;
+            }
+        }
+    };
+    *LOG_FILE.lock().unwrap() = log_file;
+    0
+}
+
+/// # Safety
+///
+/// Change the log level while the socks-hub component is running, see `socks_hub_run` for the `verbosity` values.
+/// Return 0 on success.
+#[no_mangle]
+pub unsafe extern "C" fn socks_hub_set_verbosity(verbosity: ArgVerbosity) -> c_int {
+    crate::set_verbosity(verbosity);
+    0
+}
+
+/// # Safety
+///
+/// Set the number of worker threads of the runtime built by the `socks_hub_run` function, 1 runs the hub on a single
+/// worker thread for a minimal footprint, 0 means one per CPU core, which is the default.
+/// This function must be called before the `socks_hub_run` function to take effect.
+/// Return 0 on success, or -1 if `worker_threads` is negative.
+#[no_mangle]
+pub unsafe extern "C" fn socks_hub_set_worker_threads(worker_threads: c_int) -> c_int {
+    match usize::try_from(worker_threads) {
+        Ok(worker_threads) => {
+            crate::api::set_worker_threads(worker_threads);
+            0
+        }
+        Err(_) => {
+            log::error!("invalid number of worker threads: {}", worker_threads);
+            -1
+        }
+    }
+}
+
+/// # Safety
+///
+/// Run the hub of the `socks_hub_run` function on the calling thread only, with a current-thread runtime, when
+/// `current_thread` is not 0. The number of worker threads is then ignored.
+/// This function must be called before the `socks_hub_run` function to take effect.
+/// Return 0 on success.
+#[no_mangle]
+pub unsafe extern "C" fn socks_hub_set_current_thread(current_thread: c_int) -> c_int {
+    CURRENT_THREAD.store(current_thread != 0, Ordering::Relaxed);
+    0
+}
+
 /// # Safety
 ///
 /// Shutdown the socks-hub component.
@@ -76,3 +186,14 @@ pub unsafe extern "C" fn socks_hub_run(
 pub unsafe extern "C" fn socks_hub_stop() -> c_int {
     crate::api::api_internal_stop()
 }
+
+/// # Safety
+///
+/// The cargo features the library was built with, separated by commas, such as "acl,base64,sockshub".
+/// The returned string is owned by the library and remains valid until the process exits, it must not be freed.
+#[no_mangle]
+pub unsafe extern "C" fn socks_hub_features() -> *const c_char {
+    FEATURES
+        .get_or_init(|| std::ffi::CString::new(crate::features().join(",")).unwrap())
+        .as_ptr()
+}
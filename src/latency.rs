@@ -0,0 +1,245 @@
+//! Latency diagnostics of the connections through the remote SOCKS5 servers.
+//!
+//! With `--log-latency` each connection logs how long the TCP connection to the server took, and how long the
+//! SOCKS5 handshake took, which includes the connection from the server to the destination. With
+//! `--latency-probe-interval` the tunnels also log the round-trip time to the server measured by the kernel,
+//! TCP keepalives keep the measure going while a tunnel is idle. The probes need Linux.
+//!
+//! The durations of the connections through the servers always go to the `socks_hub_upstream_connect_seconds`
+//! histogram of `GET /metrics`. With the `tracing` feature each bucket carries the latest of its connections as
+//! an exemplar, with the ID of its span, served in the OpenMetrics format.
+
+use crate::Config;
+use socks5_impl::protocol::Address;
+use std::{
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+use tokio::net::TcpStream;
+
+static LOG_LATENCY: AtomicBool = AtomicBool::new(false);
+static PROBE_INTERVAL: AtomicU64 = AtomicU64::new(0);
+static CONNECT_HISTOGRAM: Mutex<Option<Histogram>> = Mutex::new(None);
+
+/// Upper bounds in seconds of the buckets of the connect histogram, followed by `+Inf`
+const BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+pub(crate) fn init(config: &Config) {
+    LOG_LATENCY.store(config.log_latency, Ordering::Relaxed);
+    PROBE_INTERVAL.store(config.latency_probe_interval, Ordering::Relaxed);
+    #[cfg(not(target_os = "linux"))]
+    if config.latency_probe_interval > 0 {
+        log::warn!("--latency-probe-interval is not supported on this platform, ignored");
+    }
+}
+
+/// Record the durations of the connection to `server` and of the SOCKS5 handshake for `dst` in the histogram,
+/// and log them with `--log-latency`.
+pub(crate) fn record_connect(server: SocketAddr, dst: &Address, connect: Duration, handshake: Duration) {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    CONNECT_HISTOGRAM.lock().unwrap().get_or_insert_with(Histogram::default).observe(
+        (connect + handshake).as_secs_f64(),
+        crate::span::current_id(),
+        now.as_secs_f64(),
+    );
+    if LOG_LATENCY.load(Ordering::Relaxed) {
+        log::info!(
+            "{} via socks5://{}: TCP connect {:?}, SOCKS5 handshake and destination connect {:?}",
+            dst,
+            server,
+            connect,
+            handshake
+        );
+    }
+}
+
+/// Append the connect histogram to the `/metrics` text, with the exemplars in the OpenMetrics format
+pub(crate) fn write_metrics(out: &mut String, openmetrics: bool) {
+    let histogram = CONNECT_HISTOGRAM.lock().unwrap();
+    histogram.as_ref().unwrap_or(&Histogram::default()).write(out, openmetrics);
+}
+
+/// The latest connection of a bucket, with the ID of its span
+#[derive(Debug, Clone, PartialEq)]
+struct Exemplar {
+    span_id: u64,
+    value: f64,
+    timestamp: f64,
+}
+
+#[derive(Debug)]
+struct Histogram {
+    /// The number of values of each bucket, not cumulative, the last one is the `+Inf` bucket
+    counts: Vec<u64>,
+    sum: f64,
+    exemplars: Vec<Option<Exemplar>>,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            counts: vec![0; BUCKETS.len() + 1],
+            sum: 0.0,
+            exemplars: vec![None; BUCKETS.len() + 1],
+        }
+    }
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64, span_id: Option<u64>, timestamp: f64) {
+        let bucket = BUCKETS.iter().position(|le| value <= *le).unwrap_or(BUCKETS.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+        if let Some(span_id) = span_id {
+            self.exemplars[bucket] = Some(Exemplar { span_id, value, timestamp });
+        }
+    }
+
+    fn write(&self, out: &mut String, openmetrics: bool) {
+        const NAME: &str = "socks_hub_upstream_connect_seconds";
+        let _ = writeln!(
+            out,
+            "# HELP {} Time to connect through a remote SOCKS5 server, from the TCP connection to the SOCKS5 reply.",
+            NAME
+        );
+        let _ = writeln!(out, "# TYPE {} histogram", NAME);
+        let mut cumulative = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            cumulative += count;
+            let le = BUCKETS.get(bucket).map_or("+Inf".to_string(), |le| le.to_string());
+            let _ = write!(out, "{}_bucket{{le=\"{}\"}} {}", NAME, le, cumulative);
+            if let (true, Some(exemplar)) = (openmetrics, &self.exemplars[bucket]) {
+                let _ = write!(
+                    out,
+                    " # {{span_id=\"{:x}\"}} {} {:.3}",
+                    exemplar.span_id, exemplar.value, exemplar.timestamp
+                );
+            }
+            out.push('\n');
+        }
+        let _ = writeln!(out, "{}_sum {}", NAME, self.sum);
+        let _ = writeln!(out, "{}_count {}", NAME, cumulative);
+    }
+}
+
+/// Stops the round-trip time probe when dropped
+pub(crate) struct Probe(Option<tokio::task::JoinHandle<()>>);
+
+impl Drop for Probe {
+    fn drop(&mut self) {
+        if let Some(task) = self.0.take() {
+            task.abort();
+        }
+    }
+}
+
+/// Log the round-trip time of `stream`, the connection to the server of `dst`, every probe interval until the
+/// returned [`Probe`] is dropped.
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+pub(crate) fn probe(stream: &TcpStream, dst: &Address) -> Probe {
+    let interval = PROBE_INTERVAL.load(Ordering::Relaxed);
+    #[cfg(target_os = "linux")]
+    if interval > 0 {
+        let interval = Duration::from_secs(interval);
+        let socket = socket2::SockRef::from(stream);
+        let keepalive = socket2::TcpKeepalive::new().with_time(interval).with_interval(interval);
+        if let Err(err) = socket.set_tcp_keepalive(&keepalive) {
+            log::debug!("no keepalive on the connection for {}: {}", dst, err);
+        }
+        // The task owns a duplicate of the socket, dropped with the task when the tunnel ends
+        let socket = match socket.try_clone() {
+            Ok(socket) => socket,
+            Err(err) => {
+                log::debug!("no latency probe for {}: {}", dst, err);
+                return Probe(None);
+            }
+        };
+        let dst = dst.clone();
+        return Probe(Some(tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                match rtt(&socket) {
+                    Ok((rtt, rttvar)) => log::info!("{} round-trip time to the server {:?} ± {:?}", dst, rtt, rttvar),
+                    Err(err) => {
+                        log::debug!("latency probe for {} stopped: {}", dst, err);
+                        break;
+                    }
+                }
+            }
+        })));
+    }
+    Probe(None)
+}
+
+/// The smoothed round-trip time of `socket` and its variation, as estimated by the kernel
+#[cfg(target_os = "linux")]
+fn rtt(socket: &socket2::Socket) -> std::io::Result<(Duration, Duration)> {
+    use std::os::fd::AsRawFd;
+    // SAFETY: tcp_info is plain data, all zeroes is a valid value
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    // SAFETY: `info` is writable for `len` bytes and the file descriptor is open while `socket` lives
+    let ret = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut libc::tcp_info as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // TCP_ESTABLISHED of the kernel
+    if info.tcpi_state != 1 {
+        return Err(std::io::Error::new(std::io::ErrorKind::NotConnected, "connection closed"));
+    }
+    Ok((
+        Duration::from_micros(info.tcpi_rtt.into()),
+        Duration::from_micros(info.tcpi_rttvar.into()),
+    ))
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_rtt() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let _accepted = listener.accept().await.unwrap();
+    let socket = socket2::SockRef::from(&stream).try_clone().unwrap();
+    let (rtt, _) = rtt(&socket).unwrap();
+    assert!(rtt < Duration::from_secs(1));
+}
+
+#[test]
+fn test_histogram() {
+    let mut histogram = Histogram::default();
+    histogram.observe(0.003, None, 0.0);
+    histogram.observe(0.2, Some(0x2a), 1700000000.0);
+    histogram.observe(0.3, Some(0x2b), 1700000001.5);
+    histogram.observe(7.0, None, 0.0);
+    let mut prometheus = String::new();
+    histogram.write(&mut prometheus, false);
+    assert!(prometheus.contains("socks_hub_upstream_connect_seconds_bucket{le=\"0.005\"} 1\n"));
+    assert!(prometheus.contains("socks_hub_upstream_connect_seconds_bucket{le=\"0.1\"} 1\n"));
+    assert!(prometheus.contains("socks_hub_upstream_connect_seconds_bucket{le=\"0.25\"} 2\n"));
+    assert!(prometheus.contains("socks_hub_upstream_connect_seconds_bucket{le=\"+Inf\"} 4\n"));
+    assert!(prometheus.contains("socks_hub_upstream_connect_seconds_count 4\n"));
+    assert!(!prometheus.contains("span_id"));
+
+    let mut openmetrics = String::new();
+    histogram.write(&mut openmetrics, true);
+    assert!(openmetrics.contains("{le=\"0.25\"} 2 # {span_id=\"2a\"} 0.2 1700000000.000\n"));
+    assert!(openmetrics.contains("{le=\"0.5\"} 3 # {span_id=\"2b\"} 0.3 1700000001.500\n"));
+    assert!(openmetrics.contains("{le=\"+Inf\"} 4\n"));
+}
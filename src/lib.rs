@@ -1,3 +1,17 @@
+//! Convert HTTP or SOCKS5 proxy requests into connections through a remote SOCKS5 server.
+//!
+//! - [`config`] - the [`Config`] of a hub and the types of its options
+//! - [`start`] runs a hub in the background and returns a [`HubHandle`] to query its address and stop it,
+//!   [`main_entry`] runs it until a quit signal
+//! - [`server::http`] and [`server::socks5`] run a single kind of listener under a cancellation token,
+//!   [`server::socks5::Socks5Connections`] hands the accepted connections over for custom handling
+//! - [`s5_handshake`] runs the SOCKS5 handshake over a connection to the remote server opened by the caller
+//! - [`acl`] - loading and matching ACL files
+//! - [`set_decision_callback`] observes how each destination is reached, proxied, direct or denied
+//! - [`MultiLogger`] hands the log over to several sinks at once, such as the console and a [`FileLogger`]
+//! - [`features`] - the cargo features the library was built with
+//!
+
 cfg_if::cfg_if! {
     if #[cfg(feature = "base64")] {
         mod base64_wrapper;
@@ -7,8 +21,8 @@ cfg_if::cfg_if! {
 
 cfg_if::cfg_if! {
     if #[cfg(feature = "acl")] {
-        mod acl;
-        pub use acl::AccessControl;
+        pub mod acl;
+        pub use acl::{AccessControl, AclVerdict, AclWarning};
     }
 }
 
@@ -20,23 +34,91 @@ cfg_if::cfg_if! {
 //     if #[cfg(feature = "sockshub")] {
 
 #[cfg(feature = "sockshub")]
-mod config;
+pub mod config;
 #[cfg(feature = "sockshub")]
-pub use config::{ArgVerbosity, Config, Credentials, ProxyType};
+pub use config::{ArgVerbosity, Config, Credentials, DenyAction, HostPattern, LogColor, PortRange, ProxyType, ReplyAddr, Rewrite, Route};
 
 #[cfg(feature = "sockshub")]
 mod tokiort;
 #[cfg(feature = "sockshub")]
-use tokiort::TokioIo;
+pub use tokiort::TokioIo;
 
 #[cfg(feature = "sockshub")]
 mod http2socks;
 #[cfg(feature = "sockshub")]
+mod latency;
+#[cfg(feature = "sockshub")]
+mod multi_logger;
+#[cfg(feature = "sockshub")]
+mod outbound;
+#[cfg(feature = "sockshub")]
+mod privileges;
+#[cfg(feature = "sockshub")]
+mod proxy_protocol;
+#[cfg(feature = "sockshub")]
+mod resolve;
+#[cfg(feature = "sockshub")]
+mod routing;
+#[cfg(feature = "sockshub")]
+mod sni;
+#[cfg(feature = "sockshub")]
 mod socks2socks;
+#[cfg(feature = "sockshub")]
+mod span;
+#[cfg(feature = "sockshub")]
+pub use multi_logger::{FileLogger, MultiLogger};
+#[cfg(feature = "sockshub")]
+pub use routing::{clear_decision_callback, set_decision_callback, Decision};
+#[cfg(feature = "sockshub")]
+pub use socks2socks::{Socks5Connection, Socks5Connections};
 
+/// The listeners of the hub, each run until its cancellation token is cancelled
+#[cfg(feature = "sockshub")]
+pub mod server {
+    /// HTTP proxy listener
+    pub mod http {
+        pub use crate::http2socks::main_entry;
+    }
+
+    /// SOCKS5 proxy listener
+    pub mod socks5 {
+        pub use crate::socks2socks::{main_entry, Socks5Connection, Socks5Connections};
+    }
+}
+
+/// The cargo features compiled in, for the host applications to adapt to what the library supports
+pub fn features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if cfg!(feature = "acl") {
+        features.push("acl");
+    }
+    if cfg!(feature = "base64") {
+        features.push("base64");
+    }
+    if cfg!(feature = "sockshub") {
+        features.push("sockshub");
+    }
+    if cfg!(feature = "tracing") {
+        features.push("tracing");
+    }
+    features
+}
+
+#[cfg(feature = "sockshub")]
+mod accept;
+#[cfg(feature = "sockshub")]
+mod admin;
 #[cfg(feature = "sockshub")]
 mod api;
 #[cfg(feature = "sockshub")]
+mod bandwidth;
+#[cfg(feature = "sockshub")]
+mod breaker;
+#[cfg(feature = "sockshub")]
+mod cache;
+#[cfg(feature = "sockshub")]
+mod capture;
+#[cfg(feature = "sockshub")]
 mod dump_logger;
 #[cfg(feature = "sockshub")]
 mod ffi;
@@ -52,36 +134,378 @@ use socks5_impl::protocol::{Address, UserKey};
 use std::{net::SocketAddr, time::Duration};
 #[cfg(feature = "sockshub")]
 use tokio::{
-    net::{TcpStream, ToSocketAddrs},
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
     sync::mpsc::Receiver,
     time::timeout,
 };
+#[cfg(feature = "sockshub")]
+use tokio_util::sync::CancellationToken;
+
+#[cfg(feature = "sockshub")]
+pub async fn main_entry<F>(config: &Config, mut quit: Receiver<()>, callback: Option<F>) -> Result<(), BoxError>
+where
+    F: FnOnce(SocketAddr) + Send + Sync + 'static,
+{
+    let token = CancellationToken::new();
+    let quit_token = token.clone();
+    let grace = Duration::from_secs(config.shutdown_grace);
+    let quit_task = tokio::task::spawn(async move {
+        quit.recv().await;
+        log::info!("quit signal received");
+        if !grace.is_zero() {
+            admin::start_draining();
+            if timeout(grace, admin::idle()).await.is_err() {
+                let active = admin::active_connections();
+                log::info!("{} connections still active after {:?}, closing them", active, grace);
+            }
+        }
+        quit_token.cancel();
+    });
+
+    let admin = config.admin_addr.map(|addr| {
+        tokio::task::spawn(async move {
+            if let Err(err) = admin::run(addr).await {
+                log::error!("admin API on {} error: {}", addr, err);
+            }
+        })
+    });
+
+    let res = match (config.dual_listen, config.source_type) {
+        (Some(base_port), _) => dual_listen(config, base_port, token.clone(), callback).await,
+        (None, ProxyType::Http) => http2socks::main_entry(config, token.clone(), callback).await,
+        (None, ProxyType::Socks5) => socks2socks::main_entry(config, token.clone(), callback).await,
+    };
+
+    token.cancel();
+    quit_task.abort();
+    if let Some(admin) = admin {
+        admin.abort();
+    }
+    res
+}
 
+/// Run an HTTP listener on `base_port` and a SOCKS5 listener on the next port, `callback` gets the HTTP address.
+/// Both stop when either fails.
 #[cfg(feature = "sockshub")]
-pub async fn main_entry<F>(config: &Config, quit: Receiver<()>, callback: Option<F>) -> Result<(), BoxError>
+async fn dual_listen<F>(config: &Config, base_port: u16, token: CancellationToken, callback: Option<F>) -> Result<(), BoxError>
 where
     F: FnOnce(SocketAddr) + Send + Sync + 'static,
 {
-    match config.source_type {
-        ProxyType::Http => http2socks::main_entry(config, quit, callback).await,
-        ProxyType::Socks5 => socks2socks::main_entry(config, quit, callback).await,
+    let socks_port = base_port
+        .checked_add(1)
+        .ok_or_else(|| format!("--dual-listen {} leaves no port for the SOCKS5 listener", base_port))?;
+    if base_port == 0 {
+        return Err("--dual-listen needs a fixed base port".into());
     }
+    let ip = config.listen_addr.ip();
+    let mut http = config.clone();
+    http.source_type(ProxyType::Http).listen_addr(SocketAddr::new(ip, base_port));
+    let mut socks5 = config.clone();
+    socks5.source_type(ProxyType::Socks5).listen_addr(SocketAddr::new(ip, socks_port));
+    // The SOCKS5 listener is polled, and so bound, first, the HTTP listener switches the user once bound as well
+    socks5.user = None;
+    socks5.group = None;
+    tokio::try_join!(
+        socks2socks::main_entry(&socks5, token.clone(), None::<F>),
+        http2socks::main_entry(&http, token, callback),
+    )?;
+    Ok(())
+}
+
+/// Reset the state shared by the listeners for a new run with `config`, fails on an invalid configuration file
+#[cfg(feature = "sockshub")]
+pub(crate) fn init_run(config: &Config) -> Result<()> {
+    admin::reset_draining();
+    admin::set_running_config(config);
+    bandwidth::init(config);
+    breaker::init(config);
+    capture::init(config);
+    cache::init(config);
+    latency::init(config);
+    outbound::init(config)?;
+    routing::init(config);
+    if config.fallback_direct && config.no_direct {
+        log::warn!("--fallback-direct is ignored with --no-direct");
+    }
+    FALLBACK_DIRECT.store(config.fallback_direct && !config.no_direct, std::sync::atomic::Ordering::Relaxed);
+    MAX_CONNECTION_LIFETIME.store(config.max_connection_lifetime, std::sync::atomic::Ordering::Relaxed);
+    Ok(())
+}
+
+/// A hub started by [`start`]
+#[cfg(feature = "sockshub")]
+pub struct HubHandle {
+    local_addr: SocketAddr,
+    quit: tokio::sync::mpsc::Sender<()>,
+    task: tokio::task::JoinHandle<Result<()>>,
+}
+
+#[cfg(feature = "sockshub")]
+impl HubHandle {
+    /// The address the hub listens on, useful with a port 0 `listen_addr`
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stop the hub and wait for its shutdown
+    pub async fn stop(self) -> Result<()> {
+        let _ = self.quit.send(()).await;
+        self.task.await?
+    }
+}
+
+/// Run the hub of `config` in the background of the current tokio runtime, once it listens.
+#[cfg(feature = "sockshub")]
+pub async fn start(config: &Config) -> Result<HubHandle> {
+    let (quit, quit_rx) = tokio::sync::mpsc::channel(1);
+    let (addr_tx, addr_rx) = tokio::sync::oneshot::channel();
+    let config = config.clone();
+    let task = tokio::spawn(async move {
+        let callback = move |addr: SocketAddr| {
+            let _ = addr_tx.send(addr);
+        };
+        main_entry(&config, quit_rx, Some(callback)).await
+    });
+    match addr_rx.await {
+        Ok(local_addr) => Ok(HubHandle { local_addr, quit, task }),
+        // The hub stopped before listening
+        Err(_) => Err(task.await?.err().unwrap_or_else(|| "hub stopped before listening".into())),
+    }
+}
+
+/// Connect to `dst` through the remote SOCKS5 server of `config`, then close the connection.
+/// Return the time taken by the TCP connection and the SOCKS5 handshake.
+#[cfg(feature = "sockshub")]
+pub async fn test_upstream(config: &Config, dst: &str) -> Result<Duration, BoxError> {
+    let dst = Address::try_from(dst)?;
+    let auth = config.get_s5_credentials().render(std::net::Ipv4Addr::LOCALHOST.into());
+    let start = std::time::Instant::now();
+    let connector = outbound::TcpConnector;
+    s5_connect(&connector, config.server_addr, CONNECT_TIMEOUT, &dst, auth.try_into().ok(), false).await?;
+    Ok(start.elapsed())
+}
+
+/// Run the SOCKS5 handshake for `dst` with `credentials` over `stream`, a connection to a remote SOCKS5 server
+/// opened by the caller, such as a socket passed by a parent process and wrapped with
+/// [`TcpStream::from_std`]. Return the stream, a tunnel to `dst` once the server replied.
+///
+/// Nothing past the reply of the server is read, the stream can be handed back as a file descriptor.
+#[cfg(feature = "sockshub")]
+pub async fn s5_handshake<S>(mut stream: S, dst: &str, credentials: &Credentials) -> Result<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let dst = Address::try_from(dst)?;
+    let auth = credentials.render(std::net::Ipv4Addr::LOCALHOST.into()).try_into().ok();
+    timeout(CONNECT_TIMEOUT, socks5_impl::client::connect(&mut stream, &dst, auth)).await??;
+    Ok(stream)
+}
+
+/// Change the log level of the running hub, for all the loggers that honor `log::max_level`.
+#[cfg(feature = "sockshub")]
+pub fn set_verbosity(verbosity: ArgVerbosity) {
+    log::set_max_level(verbosity.into());
+    log::info!("log level set to {}", verbosity);
 }
 
 #[cfg(feature = "sockshub")]
 pub(crate) const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
 
+/// Whether `fallback_direct` is set for the running hub
 #[cfg(feature = "sockshub")]
-pub(crate) async fn create_s5_connect<A: ToSocketAddrs>(
-    server: A,
+static FALLBACK_DIRECT: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Connect to `dst` through the SOCKS5 server `server`, or directly with `fallback_direct` when the server
+/// can't be used. Return the stream with the address the server reported binding, unspecified for a direct
+/// connection.
+#[cfg(feature = "sockshub")]
+pub(crate) async fn create_s5_connect(
+    server: SocketAddr,
     dur: Duration,
     dst: &Address,
     auth: Option<UserKey>,
-) -> std::io::Result<tokio::io::BufStream<TcpStream>> {
-    let stream = timeout(dur, TcpStream::connect(server)).await??;
-    let mut stream = tokio::io::BufStream::new(stream);
-    socks5_impl::client::connect(&mut stream, dst, auth).await?;
-    Ok(stream)
+) -> std::io::Result<(tokio::io::BufStream<TcpStream>, Address)> {
+    let fallback_direct = FALLBACK_DIRECT.load(std::sync::atomic::Ordering::Relaxed);
+    s5_connect(&outbound::TcpConnector, server, dur, dst, auth, fallback_direct).await
+}
+
+/// [`create_s5_connect`] with the connections opened by `connector`
+#[cfg(feature = "sockshub")]
+async fn s5_connect<C: outbound::Connector>(
+    connector: &C,
+    server: SocketAddr,
+    dur: Duration,
+    dst: &Address,
+    auth: Option<UserKey>,
+    fallback_direct: bool,
+) -> std::io::Result<(tokio::io::BufStream<C::Stream>, Address)> {
+    // The server is unreachable or broken, as opposed to failing to connect the destination
+    let upstream_failed = |err: std::io::Error| async move {
+        if !fallback_direct {
+            return Err(err);
+        }
+        log::warn!("upstream socks5://{} failed: {}, connecting {} directly", server, err, dst);
+        let stream = timeout(dur, connector.connect_direct(dst)).await??;
+        Ok((outbound::buffered(stream), Address::unspecified()))
+    };
+    let Some(attempt) = breaker::attempt(server) else {
+        let msg = format!("upstream {} circuit open, connection not attempted", server);
+        return upstream_failed(std::io::Error::new(std::io::ErrorKind::ConnectionRefused, msg)).await;
+    };
+    let auth = outbound::credentials(server, auth);
+    let start = std::time::Instant::now();
+    let stream = match timeout(dur, connector.connect_upstream(server)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(err)) => {
+            attempt.record(false);
+            return upstream_failed(err).await;
+        }
+        Err(elapsed) => {
+            attempt.record(false);
+            return upstream_failed(elapsed.into()).await;
+        }
+    };
+    let connected = std::time::Instant::now();
+    let mut stream = outbound::buffered(stream);
+    // An upstream accepting the connection and then stalling fails like one that can't be connected
+    let handshake = match timeout(dur, socks5_impl::client::connect(&mut stream, dst, auth)).await {
+        Ok(handshake) => handshake,
+        Err(elapsed) => {
+            attempt.record(false);
+            let msg = format!("upstream socks5://{} handshake timed out: {}", server, elapsed);
+            return upstream_failed(std::io::Error::new(std::io::ErrorKind::TimedOut, msg)).await;
+        }
+    };
+    let bound = match handshake {
+        Ok(bound) => {
+            attempt.record(true);
+            latency::record_connect(server, dst, connected - start, connected.elapsed());
+            bound
+        }
+        // A failure reply for the destination, the upstream itself works
+        Err(err @ socks5_impl::Error::String(_)) => {
+            attempt.record(true);
+            return Err(err.into());
+        }
+        Err(socks5_impl::Error::Io(err))
+            if matches!(err.kind(), std::io::ErrorKind::UnexpectedEof | std::io::ErrorKind::ConnectionReset) =>
+        {
+            attempt.record(false);
+            let msg = format!("upstream socks5://{} closed during handshake: {}", server, err);
+            return upstream_failed(std::io::Error::new(err.kind(), msg)).await;
+        }
+        Err(err) => {
+            attempt.record(false);
+            return upstream_failed(err.into()).await;
+        }
+    };
+    Ok((stream, bound))
+}
+
+/// A byte count for the logs, with its value in binary units from 1 KiB on, `1258291 bytes (1.2 MiB)`
+#[cfg(feature = "sockshub")]
+pub(crate) fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+    if bytes < 1024 {
+        return format!("{} bytes", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if value < 10.0 {
+        format!("{} bytes ({:.1} {})", bytes, value, UNITS[unit])
+    } else {
+        format!("{} bytes ({:.0} {})", bytes, value, UNITS[unit])
+    }
+}
+
+/// Bind a TCP listener to `addr` with the socket options of `config`
+#[cfg(feature = "sockshub")]
+pub(crate) fn bind_listener(addr: SocketAddr, config: &Config) -> std::io::Result<tokio::net::TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    #[cfg(unix)]
+    socket.set_reuse_address(config.reuse_addr)?;
+    if config.reuse_port {
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        #[cfg(not(unix))]
+        log::warn!("SO_REUSEPORT is not supported on this platform, ignored");
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into()).map_err(|err| bind_error(addr, err))?;
+    socket.listen(1024)?;
+    tokio::net::TcpListener::from_std(socket.into())
+}
+
+/// The error of binding `addr`, with a hint on how to fix the usual causes
+#[cfg(feature = "sockshub")]
+fn bind_error(addr: SocketAddr, err: std::io::Error) -> std::io::Error {
+    use std::io::ErrorKind;
+    let msg = match err.kind() {
+        ErrorKind::PermissionDenied if addr.port() < 1024 => format!(
+            "permission denied binding {}, try running with privileges or choose a port >= 1024",
+            addr
+        ),
+        ErrorKind::AddrInUse => format!(
+            "address {} is already in use, stop the other process listening on it or choose another port",
+            addr
+        ),
+        ErrorKind::AddrNotAvailable => format!(
+            "address {} is not available, {} is not assigned to any local interface",
+            addr,
+            addr.ip()
+        ),
+        _ => format!("binding {} failed: {}", addr, err),
+    };
+    std::io::Error::new(err.kind(), msg)
+}
+
+/// The `max_connection_lifetime` of the running hub in seconds, 0 for no limit
+#[cfg(feature = "sockshub")]
+static MAX_CONNECTION_LIFETIME: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+/// Copy data in both directions between the client `a` and the server `b` until either side closes, the `token`
+/// is cancelled or the `max_connection_lifetime` elapses, within the `total_rate` of the hub, capturing the bytes
+/// with `capture_dir`. Return the number of bytes copied from `a` to `b` and from `b` to `a`.
+#[cfg(feature = "sockshub")]
+pub(crate) async fn relay<A, B>(a: &mut A, b: &mut B, token: &CancellationToken) -> std::io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let lifetime = MAX_CONNECTION_LIFETIME.load(std::sync::atomic::Ordering::Relaxed);
+    let expired = async {
+        match lifetime {
+            0 => std::future::pending().await,
+            secs => tokio::time::sleep(Duration::from_secs(secs)).await,
+        }
+    };
+    let copy = async {
+        let capture = capture::start();
+        let a = &mut capture::Captured::new(a, capture.clone(), true);
+        let b = &mut capture::Captured::new(b, capture, false);
+        match bandwidth::limiter() {
+            Some(limiter) => {
+                let mut a = bandwidth::Throttled::new(a, limiter.clone());
+                let mut b = bandwidth::Throttled::new(b, limiter);
+                tokio::io::copy_bidirectional(&mut a, &mut b).await
+            }
+            None => tokio::io::copy_bidirectional(a, b).await,
+        }
+    };
+    tokio::select! {
+        res = copy => res,
+        _ = token.cancelled() => Err(std::io::Error::new(std::io::ErrorKind::Interrupted, "relay cancelled by shutdown")),
+        _ = expired => {
+            let msg = format!("connection closed after its maximum lifetime of {}s", lifetime);
+            Err(std::io::Error::new(std::io::ErrorKind::TimedOut, msg))
+        }
+    }
 }
 
 #[cfg(feature = "sockshub")]
@@ -89,5 +513,149 @@ pub(crate) fn std_io_error_other<E: Into<BoxError>>(err: E) -> std::io::Error {
     std::io::Error::new(std::io::ErrorKind::Other, err)
 }
 
+#[cfg(feature = "sockshub")]
+#[tokio::test]
+async fn test_bind_error() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut config = Config::default();
+    config.reuse_addr(false);
+    let err = bind_listener(addr, &config).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+    assert!(err.to_string().contains(&format!("address {} is already in use", addr)), "{}", err);
+
+    let err = bind_error("0.0.0.0:80".parse().unwrap(), std::io::ErrorKind::PermissionDenied.into());
+    assert!(err.to_string().contains("choose a port >= 1024"), "{}", err);
+}
+
+#[cfg(feature = "sockshub")]
+#[tokio::test]
+async fn test_upstream_closed_during_handshake() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        drop(stream);
+    });
+    let dst = Address::from(("example.com", 80));
+    let err = create_s5_connect(server, CONNECT_TIMEOUT, &dst, None).await.unwrap_err();
+    assert!(err.to_string().contains("closed during handshake"), "{}", err);
+}
+
+#[cfg(feature = "sockshub")]
+#[tokio::test]
+async fn test_fallback_direct() {
+    let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = upstream.local_addr().unwrap();
+    drop(upstream);
+    let destination = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let dst = Address::from(destination.local_addr().unwrap());
+    let connector = outbound::TcpConnector;
+    assert!(s5_connect(&connector, server, CONNECT_TIMEOUT, &dst, None, false).await.is_err());
+    let (stream, _) = s5_connect(&connector, server, CONNECT_TIMEOUT, &dst, None, true).await.unwrap();
+    assert_eq!(stream.get_ref().peer_addr().unwrap(), destination.local_addr().unwrap());
+}
+
+/// A remote SOCKS5 server in memory, which expects the `user` and `pass` credentials and echoes the data of
+/// every CONNECT
+#[cfg(feature = "sockshub")]
+#[cfg(test)]
+struct EchoConnector;
+
+#[cfg(feature = "sockshub")]
+#[cfg(test)]
+#[async_trait::async_trait]
+impl outbound::Connector for EchoConnector {
+    type Stream = tokio::io::DuplexStream;
+
+    async fn connect_upstream(&self, _server: SocketAddr) -> std::io::Result<Self::Stream> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let (client, mut server) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            let mut methods = [0; 4];
+            server.read_exact(&mut methods).await?;
+            assert_eq!(methods, [0x05, 0x02, 0x00, 0x02]);
+            server.write_all(&[0x05, 0x02]).await?;
+            let mut auth = [0; 11];
+            server.read_exact(&mut auth).await?;
+            assert_eq!(&auth, b"\x01\x04user\x04pass");
+            server.write_all(&[0x01, 0x00]).await?;
+            let mut request = vec![0; 4 + 1 + "example.com".len() + 2];
+            server.read_exact(&mut request).await?;
+            assert_eq!(&request[..5], &[0x05, 0x01, 0x00, 0x03, 11]);
+            assert_eq!(&request[5..16], b"example.com");
+            server.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await?;
+            let (mut reader, mut writer) = tokio::io::split(server);
+            tokio::io::copy(&mut reader, &mut writer).await
+        });
+        Ok(client)
+    }
+
+    async fn connect_direct(&self, dst: &Address) -> std::io::Result<Self::Stream> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!("no direct connection to {}", dst),
+        ))
+    }
+}
+
+#[cfg(feature = "sockshub")]
+#[tokio::test]
+async fn test_s5_connect_in_memory() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let server = "192.0.2.1:1080".parse().unwrap();
+    let dst = Address::from(("example.com", 443));
+    let auth = Some(UserKey::new("user", "pass"));
+    let (mut stream, bound) = s5_connect(&EchoConnector, server, CONNECT_TIMEOUT, &dst, auth, false)
+        .await
+        .unwrap();
+    assert_eq!(bound, Address::unspecified());
+    let (mut client, mut incoming) = tokio::io::duplex(1024);
+    let relayed = tokio::spawn(async move { relay(&mut incoming, &mut stream, &CancellationToken::new()).await });
+    client.write_all(b"ping").await.unwrap();
+    let mut pong = [0; 4];
+    client.read_exact(&mut pong).await.unwrap();
+    assert_eq!(&pong, b"ping");
+    drop(client);
+    assert_eq!(relayed.await.unwrap().unwrap(), (4, 4));
+}
+
+#[cfg(feature = "sockshub")]
+#[tokio::test]
+async fn test_s5_handshake() {
+    use outbound::Connector;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let stream = EchoConnector.connect_upstream("192.0.2.1:1080".parse().unwrap()).await.unwrap();
+    let mut tunnel = s5_handshake(stream, "example.com:443", &Credentials::new("user", "pass"))
+        .await
+        .unwrap();
+    tunnel.write_all(b"ping").await.unwrap();
+    let mut pong = [0; 4];
+    tunnel.read_exact(&mut pong).await.unwrap();
+    assert_eq!(&pong, b"ping");
+    assert!(s5_handshake(tokio::io::empty(), "example.com:443", &Credentials::default())
+        .await
+        .is_err());
+}
+
+#[cfg(feature = "sockshub")]
+#[test]
+fn test_format_bytes() {
+    assert_eq!(format_bytes(0), "0 bytes");
+    assert_eq!(format_bytes(1023), "1023 bytes");
+    assert_eq!(format_bytes(1024), "1024 bytes (1.0 KiB)");
+    assert_eq!(format_bytes(348160), "348160 bytes (340 KiB)");
+    assert_eq!(format_bytes(1258291), "1258291 bytes (1.2 MiB)");
+    assert_eq!(format_bytes(u64::MAX), "18446744073709551615 bytes (16 EiB)");
+}
+
+#[test]
+fn test_features() {
+    let features = features();
+    assert_eq!(features.contains(&"acl"), cfg!(feature = "acl"));
+    assert_eq!(features.contains(&"sockshub"), cfg!(feature = "sockshub"));
+    assert_eq!(features.contains(&"tracing"), cfg!(feature = "tracing"));
+}
+
 //     }
 // }
@@ -0,0 +1,104 @@
+use std::{fs::File, io::Write, path::Path, sync::Mutex};
+
+/// A logger handing every record over to all its sinks, each deciding with its own filter whether to keep it,
+/// to log to the console and to a file at once for instance. The level is that of `log::max_level`.
+#[derive(Default)]
+pub struct MultiLogger {
+    sinks: Vec<Box<dyn log::Log>>,
+}
+
+impl MultiLogger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a sink
+    pub fn sink<L: log::Log + 'static>(mut self, sink: L) -> Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    /// Install as the global logger, with the `level` of all the sinks
+    pub fn init(self, level: log::LevelFilter) -> Result<(), log::SetLoggerError> {
+        log::set_boxed_logger(Box::new(self))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+}
+
+impl log::Log for MultiLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.sinks.iter().any(|sink| sink.enabled(metadata))
+    }
+
+    fn log(&self, record: &log::Record) {
+        for sink in &self.sinks {
+            if sink.enabled(record.metadata()) {
+                sink.log(record);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        for sink in &self.sinks {
+            sink.flush();
+        }
+    }
+}
+
+/// A sink appending the records to a file, one line each
+pub struct FileLogger {
+    file: Mutex<File>,
+}
+
+impl FileLogger {
+    /// Append to the file at `path`, created if missing
+    pub fn new<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(FileLogger { file: Mutex::new(file) })
+    }
+}
+
+impl log::Log for FileLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        // The records above `log::max_level` are already left out by the log macros
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let timestamp: chrono::DateTime<chrono::Local> = chrono::Local::now();
+        let line = format!(
+            "[{} {:<5} {}] - {}\n",
+            timestamp.format("%Y-%m-%d %H:%M:%S"),
+            record.level(),
+            record.module_path().unwrap_or(""),
+            record.args()
+        );
+        // A failing log file must not take the hub down, nothing else can be done about it
+        let _ = self.file.lock().unwrap().write_all(line.as_bytes());
+    }
+
+    fn flush(&self) {
+        let _ = self.file.lock().unwrap().flush();
+    }
+}
+
+#[test]
+fn test_multi_logger() {
+    use log::Log;
+    let path = std::env::temp_dir().join(format!("socks-hub-test-multi-logger-{}.log", std::process::id()));
+    let logger = MultiLogger::new()
+        .sink(FileLogger::new(&path).unwrap())
+        .sink(FileLogger::new(&path).unwrap());
+    logger.log(
+        &log::Record::builder()
+            .args(format_args!("hello"))
+            .level(log::Level::Error)
+            .module_path(Some("socks_hub"))
+            .build(),
+    );
+    logger.flush();
+    let content = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(content.lines().filter(|line| line.ends_with("ERROR socks_hub] - hello")).count(), 2);
+}
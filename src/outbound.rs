@@ -0,0 +1,234 @@
+//! Outbound connections to the remote SOCKS5 servers, from the interface given by `--outbound-interface`.
+//!
+//! An IP address is bound as the source address on every platform. An interface name is bound with
+//! `SO_BINDTODEVICE` on Linux and Android. On the other Unix systems, macOS included, the socket is bound to an
+//! address of the interface with the address family of the server, looked up with `getifaddrs`. On Windows the
+//! interface must be given by its IP address, a name is refused at startup.
+//!
+//! The connections are buffered by `--upstream-buffer-size` bytes in each direction.
+//!
+//! The servers listed in the `--upstream-credentials` file authenticate with their own credentials instead of
+//! `s5_username` and `s5_password`, which keeps the passwords out of the command line and the logs.
+
+use crate::Config;
+use socks5_impl::protocol::{Address, UserKey};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpSocket, TcpStream},
+};
+
+static INTERFACE: Mutex<Option<String>> = Mutex::new(None);
+static BUFFER_SIZE: AtomicUsize = AtomicUsize::new(8192);
+static CREDENTIALS: Mutex<Option<HashMap<SocketAddr, UserKey>>> = Mutex::new(None);
+
+/// Fails when the `--upstream-credentials` file can't be read or has an invalid line, or when the interface is
+/// named on a platform without support for names
+pub(crate) fn init(config: &Config) -> crate::Result<()> {
+    #[cfg(not(unix))]
+    if let Some(interface) = config.outbound_interface.as_ref().filter(|i| i.parse::<IpAddr>().is_err()) {
+        return Err(format!("--outbound-interface {} must be an IP address on this platform", interface).into());
+    }
+    *INTERFACE.lock().unwrap() = config.outbound_interface.clone();
+    BUFFER_SIZE.store(config.upstream_buffer_size, Ordering::Relaxed);
+    let credentials = match &config.upstream_credentials {
+        Some(path) => {
+            let credentials = std::fs::read_to_string(path)
+                .map_err(|err| err.to_string())
+                .and_then(|text| parse_credentials(&text, |name| std::env::var(name).ok()))
+                .map_err(|err| format!("failed to load the upstream credentials {}: {}", path.display(), err))?;
+            log::debug!("loaded the credentials of {} upstreams from {}", credentials.len(), path.display());
+            Some(credentials)
+        }
+        None => None,
+    };
+    *CREDENTIALS.lock().unwrap() = credentials;
+    Ok(())
+}
+
+/// The credentials to authenticate to `server` with, those of the `--upstream-credentials` file or `default`
+pub(crate) fn credentials(server: SocketAddr, default: Option<UserKey>) -> Option<UserKey> {
+    let credentials = CREDENTIALS.lock().unwrap();
+    match credentials.as_ref().and_then(|credentials| credentials.get(&server)) {
+        Some(key) => Some(key.clone()),
+        None => default,
+    }
+}
+
+/// Parse the lines `IP:port=username:password` or `IP:port=env:NAME` of a credentials file, `env` looks up
+/// the environment variables. Empty lines and lines starting with `#` are skipped.
+fn parse_credentials(text: &str, env: impl Fn(&str) -> Option<String>) -> Result<HashMap<SocketAddr, UserKey>, String> {
+    let mut credentials = HashMap::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let number = index + 1;
+        let (server, secret) = line
+            .split_once('=')
+            .ok_or(format!("line {}: expected IP:port=username:password", number))?;
+        let server: SocketAddr = server
+            .trim()
+            .parse()
+            .map_err(|e| format!("line {}: invalid server \"{}\": {}", number, server.trim(), e))?;
+        let secret = match secret.trim_start().strip_prefix("env:") {
+            Some(name) => env(name).ok_or(format!("line {}: environment variable {} is not set", number, name))?,
+            None => secret.trim_start().to_string(),
+        };
+        // The password may contain colons, the username can't
+        let (username, password) = secret
+            .split_once(':')
+            .ok_or(format!("line {}: expected username:password for {}", number, server))?;
+        credentials.insert(server, UserKey::new(username, password));
+    }
+    Ok(credentials)
+}
+
+/// Wrap a connection to a remote SOCKS5 server in the configured buffers. With a size of 0 every read and
+/// write goes straight to the socket.
+pub(crate) fn buffered<S: AsyncRead + AsyncWrite>(stream: S) -> tokio::io::BufStream<S> {
+    let size = BUFFER_SIZE.load(Ordering::Relaxed);
+    tokio::io::BufStream::with_capacity(size, size, stream)
+}
+
+/// Opens the connections to the remote SOCKS5 servers and to the destinations connected directly. The hub
+/// uses [`TcpConnector`], the tests can hand in-memory streams to the handshake and the relay instead.
+#[async_trait::async_trait]
+pub(crate) trait Connector: Sync {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send;
+
+    /// Connect to the remote SOCKS5 server `server`
+    async fn connect_upstream(&self, server: SocketAddr) -> std::io::Result<Self::Stream>;
+
+    /// Connect to `dst` without a SOCKS5 server
+    async fn connect_direct(&self, dst: &Address) -> std::io::Result<Self::Stream>;
+}
+
+/// The TCP connections of the hub, from the configured interface for the SOCKS5 servers
+pub(crate) struct TcpConnector;
+
+#[async_trait::async_trait]
+impl Connector for TcpConnector {
+    type Stream = TcpStream;
+
+    async fn connect_upstream(&self, server: SocketAddr) -> std::io::Result<TcpStream> {
+        connect(server).await
+    }
+
+    async fn connect_direct(&self, dst: &Address) -> std::io::Result<TcpStream> {
+        match dst {
+            Address::SocketAddress(addr) => TcpStream::connect(addr).await,
+            Address::DomainAddress(host, port) => TcpStream::connect((host.as_str(), *port)).await,
+        }
+    }
+}
+
+/// Connect to `server` from the configured interface, if any.
+pub(crate) async fn connect(server: SocketAddr) -> std::io::Result<TcpStream> {
+    let interface = INTERFACE.lock().unwrap().clone();
+    let Some(interface) = interface else {
+        return TcpStream::connect(server).await;
+    };
+    let socket = match server {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    match interface.parse::<IpAddr>() {
+        Ok(ip) => socket.bind(SocketAddr::new(ip, 0))?,
+        Err(_) => bind_interface(&socket, &interface, server)?,
+    }
+    socket.connect(server).await
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_interface(socket: &TcpSocket, interface: &str, _server: SocketAddr) -> std::io::Result<()> {
+    socket2::SockRef::from(socket).bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+fn bind_interface(socket: &TcpSocket, interface: &str, server: SocketAddr) -> std::io::Result<()> {
+    let addr = interface_addr(interface, server.is_ipv6())?;
+    log::trace!("binding the connection to {} to {} of interface {}", server, addr, interface);
+    socket.bind(addr)
+}
+
+#[cfg(not(unix))]
+fn bind_interface(_socket: &TcpSocket, interface: &str, _server: SocketAddr) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!(
+            "binding to interface {} by name is not supported on this platform, give its IP address",
+            interface
+        ),
+    ))
+}
+
+/// The first address of `interface` in the IPv6 or IPv4 family, with port 0
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+fn interface_addr(interface: &str, ipv6: bool) -> std::io::Result<SocketAddr> {
+    let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    // SAFETY: on success getifaddrs stores a list in `ifaddrs`, released below by freeifaddrs
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let mut found = None;
+    let mut cursor = ifaddrs;
+    while !cursor.is_null() && found.is_none() {
+        // SAFETY: `cursor` is a node of the list returned by getifaddrs
+        let ifa = unsafe { &*cursor };
+        cursor = ifa.ifa_next;
+        // SAFETY: `ifa_name` is a NUL terminated string
+        let name = unsafe { std::ffi::CStr::from_ptr(ifa.ifa_name) };
+        if ifa.ifa_addr.is_null() || name.to_bytes() != interface.as_bytes() {
+            continue;
+        }
+        // SAFETY: `ifa_addr` points to a socket address of the family it names
+        found = unsafe {
+            match (i32::from((*ifa.ifa_addr).sa_family), ipv6) {
+                (libc::AF_INET, false) => {
+                    let sin = &*(ifa.ifa_addr as *const libc::sockaddr_in);
+                    let ip = std::net::Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+                    Some(SocketAddr::new(ip.into(), 0))
+                }
+                (libc::AF_INET6, true) => {
+                    let sin6 = &*(ifa.ifa_addr as *const libc::sockaddr_in6);
+                    let ip = std::net::Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+                    Some(std::net::SocketAddrV6::new(ip, 0, 0, sin6.sin6_scope_id).into())
+                }
+                _ => None,
+            }
+        };
+    }
+    // SAFETY: `ifaddrs` was returned by getifaddrs and is not used afterwards
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    found.ok_or_else(|| {
+        let family = if ipv6 { "IPv6" } else { "IPv4" };
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("interface {} has no {} address", interface, family),
+        )
+    })
+}
+
+#[test]
+fn test_parse_credentials() {
+    let text = "# upstreams\n\n127.0.0.1:1080=alice:pa:ss\n[::1]:1080 = env:UPSTREAM_SECRET\n";
+    let env = |name: &str| (name == "UPSTREAM_SECRET").then(|| "bob:secret".to_string());
+    let credentials = parse_credentials(text, env).unwrap();
+    assert_eq!(credentials.len(), 2);
+    assert_eq!(credentials[&"127.0.0.1:1080".parse().unwrap()], UserKey::new("alice", "pa:ss"));
+    assert_eq!(credentials[&"[::1]:1080".parse().unwrap()], UserKey::new("bob", "secret"));
+
+    assert!(parse_credentials("127.0.0.1:1080", env).unwrap_err().starts_with("line 1:"));
+    assert!(parse_credentials("#\nlocalhost:1080=a:b", env).unwrap_err().starts_with("line 2:"));
+    assert!(parse_credentials("127.0.0.1:1080=env:MISSING", env).is_err());
+    assert!(parse_credentials("127.0.0.1:1080=nopassword", env).is_err());
+}
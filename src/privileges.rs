@@ -0,0 +1,111 @@
+//! Switch to an unprivileged user and group with `--user` and `--group` once the listener is bound, to listen on
+//! a privileged port without running as root.
+
+use crate::{Config, Result};
+
+/// Switch the process to the `user` and `group` of `config`, the group first since the user could no longer
+/// change it. Without `group` the primary group of `user` is used. Any failure is an error, the hub must not
+/// keep running with the privileges it was asked to give up.
+#[cfg(unix)]
+pub(crate) fn switch_user(config: &Config) -> Result<()> {
+    let Some((uid, gid)) = target_ids(config)? else {
+        return Ok(());
+    };
+    // Leave the supplementary groups of root, only root may change them
+    if unsafe { libc::geteuid() } == 0 && unsafe { libc::setgroups(1, &gid) } != 0 {
+        return Err(format!("failed to set the groups to {}: {}", gid, std::io::Error::last_os_error()).into());
+    }
+    if unsafe { libc::setgid(gid) } != 0 {
+        return Err(format!("failed to switch to group {}: {}", gid, std::io::Error::last_os_error()).into());
+    }
+    if let Some(uid) = uid {
+        if unsafe { libc::setuid(uid) } != 0 {
+            return Err(format!("failed to switch to user {}: {}", uid, std::io::Error::last_os_error()).into());
+        }
+        if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(format!("root privileges can be regained after switching to user {}", uid).into());
+        }
+    }
+    log::info!("switched to uid {} and gid {}", unsafe { libc::getuid() }, unsafe {
+        libc::getgid()
+    });
+    Ok(())
+}
+
+/// The UID, if `user` is set, and the GID to switch to, `None` if neither `user` nor `group` is set. A UID
+/// without a passwd entry has no primary group, `group` is then required rather than keeping the groups of root.
+#[cfg(unix)]
+fn target_ids(config: &Config) -> Result<Option<(Option<libc::uid_t>, libc::gid_t)>> {
+    let user = config.user.as_deref().map(lookup_user).transpose()?;
+    let gid = match (&config.group, user) {
+        (Some(group), _) => lookup_group(group)?,
+        (None, Some((_, Some(gid)))) => gid,
+        (None, Some((uid, None))) => {
+            return Err(format!("user {} has no passwd entry, set its group with --group", uid).into());
+        }
+        (None, None) => return Ok(None),
+    };
+    Ok(Some((user.map(|(uid, _)| uid), gid)))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn switch_user(config: &Config) -> Result<()> {
+    if config.user.is_some() || config.group.is_some() {
+        return Err("--user and --group are only supported on Unix".into());
+    }
+    Ok(())
+}
+
+/// The UID of `user`, a name or a number, with its primary GID if it has a passwd entry
+#[cfg(unix)]
+fn lookup_user(user: &str) -> Result<(libc::uid_t, Option<libc::gid_t>)> {
+    let name = std::ffi::CString::new(user)?;
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0; 16384];
+    let mut found = std::ptr::null_mut();
+    // SAFETY: the entry points into `buf`, only read while it lives
+    let ret = unsafe { libc::getpwnam_r(name.as_ptr(), &mut passwd, buf.as_mut_ptr(), buf.len(), &mut found) };
+    if ret == 0 && !found.is_null() {
+        return Ok((passwd.pw_uid, Some(passwd.pw_gid)));
+    }
+    match user.parse() {
+        Ok(uid) => Ok((uid, None)),
+        Err(_) => Err(format!("unknown user {}", user).into()),
+    }
+}
+
+/// The GID of `group`, a name or a number
+#[cfg(unix)]
+fn lookup_group(group: &str) -> Result<libc::gid_t> {
+    let name = std::ffi::CString::new(group)?;
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0; 16384];
+    let mut found = std::ptr::null_mut();
+    // SAFETY: the entry points into `buf`, only read while it lives
+    let ret = unsafe { libc::getgrnam_r(name.as_ptr(), &mut entry, buf.as_mut_ptr(), buf.len(), &mut found) };
+    if ret == 0 && !found.is_null() {
+        return Ok(entry.gr_gid);
+    }
+    group.parse().map_err(|_| format!("unknown group {}", group).into())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_lookup() {
+    assert_eq!(lookup_user("root").unwrap(), (0, Some(0)));
+    assert_eq!(lookup_user("4242").unwrap().0, 4242);
+    assert!(lookup_user("no-such-user-socks-hub").is_err());
+    assert_eq!(lookup_group("12345").unwrap(), 12345);
+    assert!(lookup_group("no-such-group-socks-hub").is_err());
+    assert!(switch_user(&Config::default()).is_ok());
+
+    let mut config = Config::default();
+    config.user("4242");
+    assert!(target_ids(&config).is_err());
+    config.group("4343");
+    assert_eq!(target_ids(&config).unwrap(), Some((Some(4242), 4343)));
+    config.user = None;
+    assert_eq!(target_ids(&config).unwrap(), Some((None, 4343)));
+    config.user("root").group = None;
+    assert_eq!(target_ids(&config).unwrap(), Some((Some(0), 0)));
+}
@@ -0,0 +1,179 @@
+//! The PROXY protocol header, v1 and v2, prepended by a load balancer to the connections accepted with
+//! `--accept-proxy-protocol`.
+//!
+//! The source address of the header replaces the peer address of the socket, for the logs, the client allow
+//! list and the `{client_ip}` token. The LOCAL command of v2 and the UNKNOWN protocol of v1, sent by health
+//! checks, keep the peer address of the socket. Only the peers in `--trusted-proxies` may send a header, anyone
+//! else could forge the client address.
+
+use crate::Config;
+use std::{
+    io::{Error, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// How long the load balancer has to send the header
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+const V1_PREFIX: &[u8] = b"PROXY";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The client address of a connection accepted from `peer`: the one of the PROXY header when `peer` is a trusted
+/// proxy, `peer` itself otherwise, the header of an untrusted peer is left unread.
+pub(crate) async fn client_addr<R: AsyncRead + Unpin>(stream: &mut R, peer: SocketAddr, config: &Config) -> std::io::Result<SocketAddr> {
+    let ip = match peer.ip() {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(peer.ip()),
+        ip => ip,
+    };
+    if !config.accept_proxy_protocol || !config.trusted_proxies.iter().any(|net| net.contains(&ip)) {
+        return Ok(peer);
+    }
+    Ok(read_header(stream).await?.unwrap_or(peer))
+}
+
+/// Read the PROXY header at the start of `stream` and return the client address it carries, `None` when the
+/// header doesn't carry one. Nothing after the header is consumed.
+pub(crate) async fn read_header<R: AsyncRead + Unpin>(stream: &mut R) -> std::io::Result<Option<SocketAddr>> {
+    match tokio::time::timeout(HEADER_TIMEOUT, read(stream)).await {
+        Ok(result) => result,
+        Err(_) => Err(Error::new(ErrorKind::TimedOut, "no PROXY protocol header received")),
+    }
+}
+
+async fn read<R: AsyncRead + Unpin>(stream: &mut R) -> std::io::Result<Option<SocketAddr>> {
+    let mut header = vec![0; V1_PREFIX.len()];
+    stream.read_exact(&mut header).await?;
+    if header == V1_PREFIX {
+        // The line is short and only read once per connection, byte by byte not to read past it
+        while !header.ends_with(b"\r\n") {
+            if header.len() == V1_MAX_LEN {
+                return Err(invalid("PROXY protocol v1 header too long"));
+            }
+            header.push(stream.read_u8().await?);
+        }
+        return parse_v1(&header);
+    }
+    header.resize(16, 0);
+    stream.read_exact(&mut header[V1_PREFIX.len()..]).await?;
+    if !header.starts_with(V2_SIGNATURE) {
+        return Err(invalid("missing PROXY protocol header"));
+    }
+    let len = usize::from(u16::from_be_bytes([header[14], header[15]]));
+    header.resize(16 + len, 0);
+    stream.read_exact(&mut header[16..]).await?;
+    parse_v2(&header)
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// Parse a v1 header line, `PROXY TCP4 <src> <dst> <src port> <dst port>\r\n`
+fn parse_v1(line: &[u8]) -> std::io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("PROXY protocol v1 header is not ASCII"))?;
+    let mut fields = line.trim_end_matches("\r\n").split(' ');
+    let malformed = || invalid("malformed PROXY protocol v1 header");
+    if fields.next() != Some("PROXY") {
+        return Err(malformed());
+    }
+    match fields.next() {
+        Some("TCP4") | Some("TCP6") => {}
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(malformed()),
+    }
+    let src = fields.next().and_then(|ip| ip.parse::<IpAddr>().ok()).ok_or_else(malformed)?;
+    let _dst = fields.next().and_then(|ip| ip.parse::<IpAddr>().ok()).ok_or_else(malformed)?;
+    let port = fields.next().and_then(|port| port.parse::<u16>().ok()).ok_or_else(malformed)?;
+    Ok(Some(SocketAddr::new(src, port)))
+}
+
+/// Parse a v2 header, the 16 bytes of the fixed part followed by the addresses and TLVs
+fn parse_v2(header: &[u8]) -> std::io::Result<Option<SocketAddr>> {
+    let (version, command) = (header[12] >> 4, header[12] & 0x0F);
+    if version != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    match command {
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("unsupported PROXY protocol command")),
+    }
+    let addrs = &header[16..];
+    let too_short = || invalid("PROXY protocol v2 header too short for its addresses");
+    // The high nibble is the address family, the low one the transport
+    match header[13] >> 4 {
+        0x1 => {
+            let addrs = addrs.get(..12).ok_or_else(too_short)?;
+            let ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            Ok(Some(SocketAddr::new(ip.into(), u16::from_be_bytes([addrs[8], addrs[9]]))))
+        }
+        0x2 => {
+            let addrs = addrs.get(..36).ok_or_else(too_short)?;
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&addrs[..16]);
+            let port = u16::from_be_bytes([addrs[32], addrs[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        // AF_UNSPEC and AF_UNIX carry no IP address
+        _ => Ok(None),
+    }
+}
+
+#[tokio::test]
+async fn test_client_addr() {
+    let mut config = Config::default();
+    config
+        .accept_proxy_protocol(true)
+        .trusted_proxies(vec!["10.0.0.0/8".parse().unwrap()]);
+    let forged: &[u8] = b"PROXY TCP4 127.0.0.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n";
+
+    // An untrusted peer is a plain client, its forged header is left to fail as a request
+    let peer: SocketAddr = "192.0.2.9:4000".parse().unwrap();
+    let mut data = forged;
+    assert_eq!(client_addr(&mut data, peer, &config).await.unwrap(), peer);
+    assert_eq!(data, forged);
+
+    let load_balancer: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+    let mut data = forged;
+    let client = client_addr(&mut data, load_balancer, &config).await.unwrap();
+    assert_eq!(client, "127.0.0.1:56324".parse().unwrap());
+    assert_eq!(data, b"GET / HTTP/1.1\r\n");
+}
+
+#[tokio::test]
+async fn test_read_header_v1() {
+    let mut data: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n";
+    let addr = read_header(&mut data).await.unwrap();
+    assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+    assert_eq!(data, b"GET / HTTP/1.1\r\n");
+
+    let mut data: &[u8] = b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 80\r\n";
+    assert_eq!(read_header(&mut data).await.unwrap(), Some("[2001:db8::1]:4000".parse().unwrap()));
+    let mut data: &[u8] = b"PROXY UNKNOWN\r\n";
+    assert_eq!(read_header(&mut data).await.unwrap(), None);
+    let mut data: &[u8] = b"PROXY TCP4 nonsense\r\n";
+    assert_eq!(read_header(&mut data).await.unwrap_err().kind(), ErrorKind::InvalidData);
+    let mut data: &[u8] = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+    assert_eq!(read_header(&mut data).await.unwrap_err().kind(), ErrorKind::InvalidData);
+}
+
+#[tokio::test]
+async fn test_read_header_v2() {
+    let mut data = V2_SIGNATURE.to_vec();
+    // PROXY over TCP4, 12 bytes of addresses and a 3 bytes TLV
+    data.extend([0x21, 0x11, 0, 15]);
+    data.extend([192, 0, 2, 1, 198, 51, 100, 1, 0xDC, 0x04, 0x01, 0xBB]);
+    data.extend([0x04, 0, 0]);
+    data.extend(b"payload");
+    let mut stream = data.as_slice();
+    let addr = read_header(&mut stream).await.unwrap();
+    assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+    assert_eq!(stream, b"payload");
+
+    let mut local = V2_SIGNATURE.to_vec();
+    local.extend([0x20, 0x00, 0, 0]);
+    assert_eq!(read_header(&mut local.as_slice()).await.unwrap(), None);
+}
@@ -0,0 +1,231 @@
+//! The Tor RESOLVE (0xF0) and RESOLVE_PTR (0xF1) SOCKS5 commands, enabled by the `--tor-resolve` option.
+//!
+//! RESOLVE asks for an address of a host name and RESOLVE_PTR for the host name of an address, nothing is
+//! connected. The answer is sent back as the bound address of the reply, with port 0. The request is passed
+//! to the remote SOCKS5 server, and answered locally when the ACL connects the host directly or when the
+//! server replies that it does not support the command.
+
+use crate::{
+    config::DenyAction,
+    routing::{self, Decision},
+    Config,
+};
+use socks5_impl::protocol::{
+    handshake::{self, password_method},
+    Address, AsyncStreamOperation, AuthMethod, Reply, Response, StreamOperation, UserKey,
+};
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+pub(crate) const RESOLVE: u8 = 0xF0;
+pub(crate) const RESOLVE_PTR: u8 = 0xF1;
+
+/// Whether the request waiting on `stream` is a RESOLVE or RESOLVE_PTR, the request is not consumed.
+pub(crate) async fn is_resolve_request(stream: &TcpStream) -> std::io::Result<bool> {
+    let mut buf = [0; 2];
+    let n = stream.peek(&mut buf).await?;
+    Ok(n == buf.len() && buf[0] == 0x05 && matches!(buf[1], RESOLVE | RESOLVE_PTR))
+}
+
+/// Read the RESOLVE or RESOLVE_PTR request waiting on `stream` and reply to it.
+pub(crate) async fn handle(stream: &mut TcpStream, config: &Config, s5_auth: Option<UserKey>) -> crate::Result<()> {
+    let mut header = [0; 3];
+    stream.read_exact(&mut header).await?;
+    let command = header[1];
+    let dst = Address::retrieve_from_async_stream(stream).await?;
+    let dst = if command == RESOLVE { routing::rewrite(config, dst) } else { dst };
+    crate::span::record_destination(&dst);
+
+    let (reply, address) = match routing::decide(&dst) {
+        Decision::Deny(rule) => {
+            log::info!("resolution of {} denied by rule {}", dst, rule);
+            if config.deny_action == DenyAction::Drop {
+                return Ok(());
+            }
+            (Reply::ConnectionNotAllowed, Address::unspecified())
+        }
+        Decision::Direct => resolve_locally(command, &dst).await,
+        Decision::Proxy => {
+            let server = routing::upstream(config, &dst);
+            match resolve_upstream(server, routing::connect_timeout(&dst), command, &dst, s5_auth).await {
+                Ok(Response {
+                    reply: Reply::CommandNotSupported,
+                    ..
+                }) => {
+                    log::debug!(
+                        "socks5://{} does not support command {:#x}, resolving {} locally",
+                        server,
+                        command,
+                        dst
+                    );
+                    resolve_locally(command, &dst).await
+                }
+                Ok(resp) => (resp.reply, resp.address),
+                Err(err) => {
+                    log::error!("resolving {} via socks5://{} failed: {}", dst, server, err);
+                    (Reply::GeneralFailure, Address::unspecified())
+                }
+            }
+        }
+    };
+    log::debug!("resolved {} to {} ({:?})", dst, address, reply);
+    Response::new(reply, address).write_to_async_stream(stream).await?;
+    Ok(())
+}
+
+/// Send the request to the SOCKS5 server `server` and return its reply, the whole exchange within `dur`. The
+/// outcome counts for the circuit breaker of the server like that of a connection.
+async fn resolve_upstream(
+    server: SocketAddr,
+    dur: Duration,
+    command: u8,
+    dst: &Address,
+    auth: Option<UserKey>,
+) -> std::io::Result<Response> {
+    let Some(attempt) = crate::breaker::attempt(server) else {
+        let msg = format!("upstream {} circuit open, resolution not attempted", server);
+        return Err(std::io::Error::new(std::io::ErrorKind::ConnectionRefused, msg));
+    };
+    let auth = crate::outbound::credentials(server, auth);
+    // An upstream accepting the connection and then stalling fails like one that can't be connected
+    let res = match timeout(dur, exchange(server, command, dst, auth)).await {
+        Ok(res) => res,
+        Err(elapsed) => {
+            let msg = format!("upstream socks5://{} timed out: {}", server, elapsed);
+            Err(std::io::Error::new(std::io::ErrorKind::TimedOut, msg))
+        }
+    };
+    // Any reply, a failure to resolve included, shows the server works
+    attempt.record(res.is_ok());
+    res
+}
+
+async fn exchange(server: SocketAddr, command: u8, dst: &Address, auth: Option<UserKey>) -> std::io::Result<Response> {
+    let mut stream = crate::outbound::connect(server).await?;
+
+    let mut methods = vec![AuthMethod::NoAuth];
+    if auth.is_some() {
+        methods.push(AuthMethod::UserPass);
+    }
+    handshake::Request::new(methods).write_to_async_stream(&mut stream).await?;
+    let method = handshake::Response::retrieve_from_async_stream(&mut stream).await?.method;
+    match (method, auth) {
+        (AuthMethod::NoAuth, _) => {}
+        (AuthMethod::UserPass, Some(auth)) => {
+            password_method::Request::new(&auth.username, &auth.password)
+                .write_to_async_stream(&mut stream)
+                .await?;
+            let resp = password_method::Response::retrieve_from_async_stream(&mut stream).await?;
+            if resp.status != password_method::Status::Succeeded {
+                return Err(crate::std_io_error_other(format!("authentication to socks5://{} failed", server)));
+            }
+        }
+        (method, _) => {
+            return Err(crate::std_io_error_other(format!("unsupported authentication method {:?}", method)));
+        }
+    }
+
+    let mut request = vec![0x05, command, 0x00];
+    dst.write_to_buf(&mut request);
+    stream.write_all(&request).await?;
+    Response::retrieve_from_async_stream(&mut stream).await
+}
+
+/// Answer the request with the system resolver.
+async fn resolve_locally(command: u8, dst: &Address) -> (Reply, Address) {
+    let result = match (command, dst) {
+        (RESOLVE, Address::DomainAddress(host, _)) => tokio::net::lookup_host((host.as_str(), 0)).await.and_then(|mut addrs| {
+            addrs
+                .next()
+                .map(|addr| Address::from(SocketAddr::new(addr.ip(), 0)))
+                .ok_or_else(|| crate::std_io_error_other("no address found"))
+        }),
+        (RESOLVE, Address::SocketAddress(addr)) => Ok(Address::from(SocketAddr::new(addr.ip(), 0))),
+        (RESOLVE_PTR, Address::SocketAddress(addr)) => {
+            let ip = addr.ip();
+            match tokio::task::spawn_blocking(move || reverse_lookup(ip)).await {
+                Ok(result) => result.map(|host| Address::from((host, 0))),
+                Err(err) => Err(crate::std_io_error_other(err)),
+            }
+        }
+        _ => return (Reply::AddressTypeNotSupported, Address::unspecified()),
+    };
+    match result {
+        Ok(address) => (Reply::Succeeded, address),
+        Err(err) => {
+            log::debug!("resolving {} locally failed: {}", dst, err);
+            (Reply::HostUnreachable, Address::unspecified())
+        }
+    }
+}
+
+#[cfg(unix)]
+fn reverse_lookup(ip: IpAddr) -> std::io::Result<String> {
+    const NI_MAXHOST: usize = 1025;
+    let addr = socket2::SockAddr::from(SocketAddr::new(ip, 0));
+    let mut host = [0 as libc::c_char; NI_MAXHOST];
+    // SAFETY: `addr` is a valid socket address of `addr.len()` bytes and `host` is writable for its whole length
+    let ret = unsafe {
+        libc::getnameinfo(
+            addr.as_ptr() as *const libc::sockaddr,
+            addr.len(),
+            host.as_mut_ptr(),
+            host.len() as libc::socklen_t,
+            std::ptr::null_mut(),
+            0,
+            libc::NI_NAMEREQD,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("no host name found for {}", ip),
+        ));
+    }
+    // SAFETY: getnameinfo wrote a NUL terminated string into `host`
+    let host = unsafe { std::ffi::CStr::from_ptr(host.as_ptr()) };
+    Ok(host.to_string_lossy().into_owned())
+}
+
+#[cfg(not(unix))]
+fn reverse_lookup(ip: IpAddr) -> std::io::Result<String> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!("reverse lookup of {} is not supported on this platform", ip),
+    ))
+}
+
+#[tokio::test]
+async fn test_resolve_locally() {
+    let addr = Address::from(SocketAddr::from(([10, 1, 2, 3], 80)));
+    let (reply, address) = resolve_locally(RESOLVE, &addr).await;
+    assert_eq!(reply, Reply::Succeeded);
+    assert_eq!(address, Address::from(SocketAddr::from(([10, 1, 2, 3], 0))));
+
+    let (reply, _) = resolve_locally(RESOLVE_PTR, &Address::from(("example.com", 0))).await;
+    assert_eq!(reply, Reply::AddressTypeNotSupported);
+}
+
+#[tokio::test]
+async fn test_resolve_upstream_timeout() {
+    // A server accepting the connection and never answering the handshake
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = listener.local_addr().unwrap();
+    let _accept = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        drop(stream);
+    });
+    let dst = Address::from(("example.com", 0));
+    let err = resolve_upstream(server, Duration::from_millis(200), RESOLVE, &dst, None)
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+}
@@ -0,0 +1,524 @@
+//! Decide how a destination is reached: through the remote SOCKS5 server, directly, or not at all.
+
+use crate::{Config, HostPattern};
+use socks5_impl::protocol::Address;
+use std::{
+    net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+#[cfg(feature = "acl")]
+static ACL_CENTER: Mutex<Option<Arc<crate::acl::AccessControl>>> = Mutex::new(None);
+
+static NO_DIRECT: AtomicBool = AtomicBool::new(false);
+static BLOCK_HOSTS: Mutex<Vec<HostPattern>> = Mutex::new(Vec::new());
+static ALLOW_HOSTS: Mutex<Vec<HostPattern>> = Mutex::new(Vec::new());
+/// The hosts of the `NO_PROXY` environment variable, connected directly
+static NO_PROXY: Mutex<Vec<NoProxy>> = Mutex::new(Vec::new());
+/// The bits of the `--proxy-sample-rate` fraction, 1.0 proxies every connection the ACL doesn't bypass
+static PROXY_SAMPLE_RATE: AtomicU64 = AtomicU64::new(0x3FF0_0000_0000_0000);
+
+static DECISION_CALLBACK: Mutex<Option<Arc<DecisionCallback>>> = Mutex::new(None);
+
+type DecisionCallback = dyn Fn(&Address, &Decision) + Send + Sync;
+
+static PROXIED: AtomicU64 = AtomicU64::new(0);
+static DIRECT: AtomicU64 = AtomicU64::new(0);
+static DENIED: AtomicU64 = AtomicU64::new(0);
+
+/// How a destination is reached, as decided by the host filters, `NO_PROXY` and the ACL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    /// Connect through the remote SOCKS5 server
+    Proxy,
+    /// Connect to the destination directly
+    Direct,
+    /// Refuse the connection, with the rule that denied it
+    Deny(String),
+}
+
+/// Call `callback` with the destination and the decision each time a connection path decides how to reach its
+/// destination, after the ACL was consulted, replacing the previous callback. It runs on the connection tasks
+/// and must not block.
+pub fn set_decision_callback<F>(callback: F)
+where
+    F: Fn(&Address, &Decision) + Send + Sync + 'static,
+{
+    *DECISION_CALLBACK.lock().unwrap() = Some(Arc::new(callback));
+}
+
+/// Remove the callback set by [`set_decision_callback`]
+pub fn clear_decision_callback() {
+    *DECISION_CALLBACK.lock().unwrap() = None;
+}
+
+/// Load the routing rules from the config, the ACL of a previous run is replaced.
+pub(crate) fn init(config: &Config) {
+    NO_DIRECT.store(config.no_direct, Ordering::Relaxed);
+    *BLOCK_HOSTS.lock().unwrap() = config.block_hosts.clone();
+    *ALLOW_HOSTS.lock().unwrap() = config.allow_hosts.clone();
+    let sample_rate = match config.proxy_sample_rate {
+        Some(_) if config.no_direct => {
+            log::warn!("--proxy-sample-rate is ignored with --no-direct");
+            1.0
+        }
+        Some(rate) => rate,
+        None => 1.0,
+    };
+    PROXY_SAMPLE_RATE.store(sample_rate.to_bits(), Ordering::Relaxed);
+    let no_proxy = match config.no_proxy_env {
+        true => std::env::var("NO_PROXY").or_else(|_| std::env::var("no_proxy")).unwrap_or_default(),
+        false => String::new(),
+    };
+    let no_proxy = parse_no_proxy(&no_proxy);
+    if !no_proxy.is_empty() {
+        log::info!("connecting the {} NO_PROXY hosts directly", no_proxy.len());
+    }
+    *NO_PROXY.lock().unwrap() = no_proxy;
+    #[cfg(feature = "acl")]
+    {
+        let acl = config.acl_file.as_ref().and_then(|acl_file| {
+            crate::acl::AccessControl::load_from_file(acl_file)
+                .map_err(|err| log::error!("failed to load ACL file {:?}: {}", acl_file, err))
+                .ok()
+        });
+        *ACL_CENTER.lock().unwrap() = acl.map(Arc::new);
+    }
+}
+
+/// The ACL of the running hub, if any
+#[cfg(feature = "acl")]
+fn acl() -> Option<Arc<crate::acl::AccessControl>> {
+    ACL_CENTER.lock().unwrap().clone()
+}
+
+/// Decide how to reach `dst`, and count the decision.
+pub(crate) fn decide(dst: &Address) -> Decision {
+    let mut decision = rule_decision(dst);
+    let sample_rate = f64::from_bits(PROXY_SAMPLE_RATE.load(Ordering::Relaxed));
+    if decision == Decision::Proxy && sample_rate < 1.0 {
+        if sample() >= sample_rate {
+            decision = Decision::Direct;
+        }
+        log::info!("{} sampled {:?} with a proxy sample rate of {}", dst, decision, sample_rate);
+    }
+    let counter = match decision {
+        Decision::Proxy => &PROXIED,
+        Decision::Direct => &DIRECT,
+        Decision::Deny(_) => &DENIED,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+    let callback = DECISION_CALLBACK.lock().unwrap().clone();
+    if let Some(callback) = callback {
+        callback(dst, &decision);
+    }
+    decision
+}
+
+/// How the host filters, `NO_PROXY`, the ACL and `--no-direct` reach `dst`, without the proxy sampling, the
+/// counters or the decision callback, for a connection [`decide`] already counted
+pub(crate) fn rule_decision(dst: &Address) -> Decision {
+    let host = dst.domain();
+    let filtered = filter_host(&host, &BLOCK_HOSTS.lock().unwrap(), &ALLOW_HOSTS.lock().unwrap());
+    let mut decision = match filtered {
+        Some(rule) => Decision::Deny(rule),
+        None => acl_decision(dst),
+    };
+    decision = apply_no_proxy(decision, dst, &NO_PROXY.lock().unwrap());
+    if decision == Decision::Direct && NO_DIRECT.load(Ordering::Relaxed) {
+        log::debug!("{} is proxied, direct connections are disabled", dst);
+        decision = Decision::Proxy;
+    }
+    decision
+}
+
+/// The rule denying `host` by the `--block-hosts` and `--allow-hosts` patterns, `None` if they let it through
+fn filter_host(host: &str, block: &[HostPattern], allow: &[HostPattern]) -> Option<String> {
+    if let Some(pattern) = block.iter().find(|pattern| pattern.matches(host)) {
+        return Some(format!("--block-hosts {}", pattern));
+    }
+    if !allow.is_empty() && !allow.iter().any(|pattern| pattern.matches(host)) {
+        return Some("--allow-hosts".to_string());
+    }
+    None
+}
+
+/// Connect `dst` directly if it matches a `NO_PROXY` rule and `decision` would proxy it, a denied `dst` stays denied
+fn apply_no_proxy(decision: Decision, dst: &Address, rules: &[NoProxy]) -> Decision {
+    if decision == Decision::Proxy && rules.iter().any(|rule| rule.matches(dst)) {
+        log::debug!("{} is connected directly, it matches NO_PROXY", dst);
+        return Decision::Direct;
+    }
+    decision
+}
+
+/// An entry of the `NO_PROXY` environment variable
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum NoProxy {
+    /// `*`, every destination
+    Any,
+    /// A domain and its subdomains, written `example.com`, `.example.com` or `*.example.com`
+    Domain(String),
+    /// An IP address or a CIDR range, IP destinations only, the domains are not resolved
+    Net(ipnet::IpNet),
+}
+
+impl NoProxy {
+    fn matches(&self, dst: &Address) -> bool {
+        match (self, dst) {
+            (NoProxy::Any, _) => true,
+            (NoProxy::Net(net), Address::SocketAddress(addr)) => net.contains(&addr.ip()),
+            (NoProxy::Net(_), Address::DomainAddress(..)) => false,
+            (NoProxy::Domain(domain), _) => {
+                let host = dst.domain().trim_end_matches('.').to_ascii_lowercase();
+                host == *domain || host.strip_suffix(domain.as_str()).is_some_and(|sub| sub.ends_with('.'))
+            }
+        }
+    }
+}
+
+/// Parse the comma separated entries of `NO_PROXY`, ports and invalid entries are ignored
+fn parse_no_proxy(value: &str) -> Vec<NoProxy> {
+    let mut rules = Vec::new();
+    for entry in value.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        if entry == "*" {
+            rules.push(NoProxy::Any);
+            continue;
+        }
+        if let Ok(net) = entry.parse::<ipnet::IpNet>() {
+            rules.push(NoProxy::Net(net));
+            continue;
+        }
+        // `[::1]:8080`, `10.0.0.1:8080` or `example.com:8080`
+        let host = match entry.strip_prefix('[').and_then(|entry| entry.split_once(']')) {
+            Some((ip, _)) => ip,
+            None if entry.matches(':').count() > 1 => entry,
+            None => entry.split_once(':').map_or(entry, |(host, _)| host),
+        };
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            rules.push(NoProxy::Net(ipnet::IpNet::from(ip)));
+            continue;
+        }
+        let domain = host.trim_start_matches('*').trim_start_matches('.').trim_end_matches('.');
+        if domain.is_empty() || domain.contains(['*', '/']) {
+            log::warn!("invalid NO_PROXY entry {}, ignored", entry);
+            continue;
+        }
+        rules.push(NoProxy::Domain(domain.to_ascii_lowercase()));
+    }
+    rules
+}
+
+/// A random number in `[0, 1)`, with the randomly seeded keys of the std hasher
+fn sample() -> f64 {
+    use std::hash::{BuildHasher, Hasher};
+    let random = std::collections::hash_map::RandomState::new().build_hasher().finish();
+    // The 53 bits of the mantissa
+    (random >> 11) as f64 / (1_u64 << 53) as f64
+}
+
+#[cfg_attr(not(feature = "acl"), allow(unused_variables))]
+fn acl_decision(dst: &Address) -> Decision {
+    #[cfg(feature = "acl")]
+    if let Some(acl) = acl() {
+        return acl_verdict(&acl, dst);
+    }
+    Decision::Proxy
+}
+
+#[cfg(feature = "acl")]
+fn acl_verdict(acl: &crate::acl::AccessControl, dst: &Address) -> Decision {
+    use crate::acl::AclVerdict;
+    let verdict = acl.match_host_port(&dst.domain(), dst.port());
+    if let AclVerdict::Proxy(Some(rule)) | AclVerdict::Direct(Some(rule)) | AclVerdict::Deny(rule) = &verdict {
+        match acl.rule_source(rule) {
+            Some(source) => log::debug!("{} matched ACL rule {}", dst, source),
+            None => log::debug!("{} matched ACL rule {}", dst, rule),
+        }
+    }
+    // Hosts not matching any rule are connected directly
+    match verdict {
+        AclVerdict::Deny(rule) => Decision::Deny(rule),
+        AclVerdict::Proxy(Some(_)) => Decision::Proxy,
+        AclVerdict::Proxy(None) | AclVerdict::Direct(_) => Decision::Direct,
+    }
+}
+
+/// Check if the client `ip` may connect, by the `allow_clients` ranges of `config` and the `client` rules of the ACL
+pub(crate) fn is_client_allowed(config: &Config, ip: IpAddr) -> bool {
+    if !config.is_client_allowed(ip) {
+        return false;
+    }
+    #[cfg(feature = "acl")]
+    if let Some(acl) = acl() {
+        return acl.check_client_allowed(ip);
+    }
+    true
+}
+
+/// The connect timeout of `dst`, from the `timeout=` option of the ACL rule it matches or [`crate::CONNECT_TIMEOUT`]
+#[cfg_attr(not(feature = "acl"), allow(unused_variables))]
+pub(crate) fn connect_timeout(dst: &Address) -> std::time::Duration {
+    #[cfg(feature = "acl")]
+    if let Some(acl) = acl() {
+        if let Some(timeout) = acl.connect_timeout_port(&dst.domain(), dst.port()) {
+            return timeout;
+        }
+    }
+    crate::CONNECT_TIMEOUT
+}
+
+/// Number of destinations sent through a SOCKS5 server, connected directly and denied so far
+pub(crate) fn decision_counts() -> (u64, u64, u64) {
+    (
+        PROXIED.load(Ordering::Relaxed),
+        DIRECT.load(Ordering::Relaxed),
+        DENIED.load(Ordering::Relaxed),
+    )
+}
+
+/// The SOCKS5 server of the first `routes` entry matching the destination host, `server_addr` otherwise.
+pub(crate) fn upstream(config: &Config, dst: &Address) -> SocketAddr {
+    let host = dst.domain();
+    config
+        .routes
+        .iter()
+        .find(|route| route.pattern.matches(&host))
+        .map_or(config.server_addr, |route| route.upstream)
+}
+
+/// Whether `reject_when_down` refuses the new client requests, the circuits of all the remote SOCKS5 servers
+/// being open
+pub(crate) fn upstreams_down(config: &Config) -> bool {
+    if !config.reject_when_down || (config.fallback_direct && !config.no_direct) {
+        return false;
+    }
+    let upstreams = std::iter::once(config.server_addr).chain(config.routes.iter().map(|route| route.upstream));
+    crate::breaker::all_open(upstreams)
+}
+
+/// The destination of a request for `host`, as found in a URI. A bracketed IPv6 address is a socket address,
+/// with its zone, `[fe80::1%25eth0]` or `[fe80::1%eth0]`, as the scope ID, other hosts are kept as domains.
+/// The scope is only used for direct connections, SOCKS5 has no room for it.
+pub(crate) fn destination(host: &str, port: u16) -> Address {
+    let Some(literal) = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')) else {
+        return Address::from((host, port));
+    };
+    let (ip, zone) = match literal.split_once('%') {
+        // The `%` of the zone is written `%25` in URIs
+        Some((ip, zone)) => (ip, Some(zone.strip_prefix("25").filter(|zone| !zone.is_empty()).unwrap_or(zone))),
+        None => (literal, None),
+    };
+    let Ok(ip) = ip.parse::<Ipv6Addr>() else {
+        return Address::from((host, port));
+    };
+    let scope_id = zone.map_or(0, |zone| {
+        scope_id(zone).unwrap_or_else(|| {
+            log::warn!("unknown zone {} of {}, ignored", zone, host);
+            0
+        })
+    });
+    Address::from(SocketAddr::from(SocketAddrV6::new(ip, port, 0, scope_id)))
+}
+
+/// The scope ID of an IPv6 zone, numeric or an interface name
+fn scope_id(zone: &str) -> Option<u32> {
+    if let Ok(id) = zone.parse() {
+        return Some(id);
+    }
+    #[cfg(unix)]
+    {
+        let name = std::ffi::CString::new(zone).ok()?;
+        // SAFETY: `name` is a NUL terminated string
+        let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        (index != 0).then_some(index)
+    }
+    #[cfg(not(unix))]
+    None
+}
+
+/// Apply the first matching `rewrites` entry to the destination host, the port is kept.
+pub(crate) fn rewrite(config: &Config, dst: Address) -> Address {
+    let host = dst.domain();
+    match config.rewrites.iter().find_map(|rewrite| rewrite.apply(&host)) {
+        Some(new_host) => {
+            let new_dst = Address::from((new_host, dst.port()));
+            log::info!("destination {} rewritten to {}", dst, new_dst);
+            new_dst
+        }
+        None => dst,
+    }
+}
+
+/// Whether a connection to `addr` reaches the hub listening on `listen_addr`.
+pub(crate) fn is_own_listener(listen_addr: SocketAddr, addr: SocketAddr) -> bool {
+    if addr.port() != listen_addr.port() {
+        return false;
+    }
+    let ip = listen_addr.ip();
+    addr.ip() == ip || (ip.is_unspecified() && (addr.ip().is_loopback() || addr.ip().is_unspecified()))
+}
+
+/// Whether relaying to `dst` through `upstream` comes straight back to the hub listening on `listen_addr`,
+/// when `upstream` runs on this host.
+pub(crate) fn loops_through(listen_addr: SocketAddr, upstream: SocketAddr, dst: &Address) -> bool {
+    match dst {
+        Address::SocketAddress(addr) => upstream.ip().is_loopback() && is_own_listener(listen_addr, *addr),
+        Address::DomainAddress(..) => false,
+    }
+}
+
+/// Refuse a config whose remote SOCKS5 servers include the hub listening on `listen_addr` itself.
+pub(crate) fn check_loop(config: &Config, listen_addr: SocketAddr) -> std::io::Result<()> {
+    let upstreams = std::iter::once(config.server_addr).chain(config.routes.iter().map(|route| route.upstream));
+    for upstream in upstreams {
+        if is_own_listener(listen_addr, upstream) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "remote SOCKS5 server {} is this hub listening on {}, connections would loop",
+                    upstream, listen_addr
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The error of a connection refused because its destination is the hub itself
+pub(crate) fn loop_error(dst: &Address) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::ConnectionRefused,
+        format!("destination {} is this hub, refusing a proxy loop", dst),
+    )
+}
+
+#[test]
+fn test_destination() {
+    let scoped = |addr: Address| match addr {
+        Address::SocketAddress(SocketAddr::V6(addr)) => Some((*addr.ip(), addr.port(), addr.scope_id())),
+        _ => None,
+    };
+    let ip: Ipv6Addr = "fe80::1".parse().unwrap();
+    assert_eq!(scoped(destination("[fe80::1%253]", 80)), Some((ip, 80, 3)));
+    assert_eq!(scoped(destination("[fe80::1%3]", 80)), Some((ip, 80, 3)));
+    assert_eq!(scoped(destination("[fe80::1]", 443)), Some((ip, 443, 0)));
+    #[cfg(target_os = "linux")]
+    {
+        let lo = scope_id("lo").unwrap();
+        assert_eq!(scoped(destination("[fe80::1%25lo]", 80)), Some((ip, 80, lo)));
+    }
+    assert_eq!(destination("example.com", 80), Address::from(("example.com", 80)));
+    assert_eq!(destination("[not-ip]", 80), Address::from(("[not-ip]", 80)));
+}
+
+#[test]
+fn test_proxy_loop() {
+    let listen: SocketAddr = "127.0.0.1:1080".parse().unwrap();
+    assert!(is_own_listener(listen, listen));
+    assert!(!is_own_listener(listen, "127.0.0.1:1081".parse().unwrap()));
+    assert!(!is_own_listener(listen, "127.0.0.2:1080".parse().unwrap()));
+    let any: SocketAddr = "0.0.0.0:1080".parse().unwrap();
+    assert!(is_own_listener(any, "127.0.0.1:1080".parse().unwrap()));
+    assert!(!is_own_listener(any, "10.0.0.1:1080".parse().unwrap()));
+
+    let local_upstream: SocketAddr = "127.0.0.1:9050".parse().unwrap();
+    let remote_upstream: SocketAddr = "10.0.0.1:9050".parse().unwrap();
+    assert!(loops_through(listen, local_upstream, &Address::from(listen)));
+    assert!(!loops_through(listen, remote_upstream, &Address::from(listen)));
+
+    let mut config = Config::new(listen, listen);
+    assert!(check_loop(&config, listen).is_err());
+    config.server_addr(local_upstream);
+    assert!(check_loop(&config, listen).is_ok());
+}
+
+#[test]
+fn test_sample() {
+    let samples: Vec<f64> = (0..1000).map(|_| sample()).collect();
+    assert!(samples.iter().all(|s| (0.0..1.0).contains(s)));
+    let below_half = samples.iter().filter(|s| **s < 0.5).count();
+    assert!((350..650).contains(&below_half), "{}", below_half);
+}
+
+#[test]
+fn test_filter_host() {
+    let patterns = |list: &[&str]| list.iter().map(|p| p.parse().unwrap()).collect::<Vec<HostPattern>>();
+    let block = patterns(&["ads.example.com", "*.tracker.net"]);
+    let allow = patterns(&["*.example.com"]);
+    assert_eq!(filter_host("www.example.com", &block, &[]), None);
+    assert_eq!(
+        filter_host("ads.example.com", &block, &allow),
+        Some("--block-hosts ads.example.com".into())
+    );
+    assert_eq!(
+        filter_host("x.tracker.net", &block, &[]),
+        Some("--block-hosts *.tracker.net".into())
+    );
+    assert_eq!(filter_host("www.example.com", &block, &allow), None);
+    assert_eq!(filter_host("example.org", &block, &allow), Some("--allow-hosts".into()));
+    assert_eq!(filter_host("example.org", &[], &[]), None);
+}
+
+#[test]
+fn test_no_proxy() {
+    let rules = parse_no_proxy("localhost, .example.com,*.test.org,10.0.0.0/8,[::1]:8080,192.168.1.1:80,a*b,");
+    let matches = |dst: Address| rules.iter().any(|rule| rule.matches(&dst));
+    assert_eq!(rules.len(), 6);
+    assert!(matches(Address::from(("localhost", 80))));
+    assert!(matches(Address::from(("example.com", 80))));
+    assert!(matches(Address::from(("www.Example.com.", 443))));
+    assert!(!matches(Address::from(("badexample.com", 80))));
+    assert!(matches(Address::from(("a.test.org", 80))));
+    assert!(matches(Address::from("10.1.2.3:80".parse::<SocketAddr>().unwrap())));
+    assert!(!matches(Address::from("11.1.2.3:80".parse::<SocketAddr>().unwrap())));
+    assert!(matches(Address::from("[::1]:443".parse::<SocketAddr>().unwrap())));
+    assert!(matches(Address::from("192.168.1.1:443".parse::<SocketAddr>().unwrap())));
+    assert!(!matches(Address::from(("example.org", 80))));
+    assert_eq!(parse_no_proxy("*"), vec![NoProxy::Any]);
+    assert!(parse_no_proxy("").is_empty());
+}
+
+#[test]
+fn test_decision_callback() {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let recorder = seen.clone();
+    set_decision_callback(move |dst, decision| {
+        if dst.domain() == "decision-callback.test" {
+            recorder.lock().unwrap().push((dst.port(), decision.clone()));
+        }
+    });
+    let decision = decide(&Address::from(("decision-callback.test", 443)));
+    // Re-checks are neither counted nor reported
+    assert_eq!(rule_decision(&Address::from(("decision-callback.test", 8443))), decision);
+    clear_decision_callback();
+    decide(&Address::from(("decision-callback.test", 80)));
+    assert_eq!(*seen.lock().unwrap(), vec![(443, decision)]);
+}
+
+#[cfg(feature = "acl")]
+#[test]
+fn test_no_proxy_keeps_acl_denies() {
+    let path = std::env::temp_dir().join(format!("socks-hub-test-no-proxy-{}.acl", std::process::id()));
+    std::fs::write(
+        &path,
+        "[bypass_all]\n[proxy_list]\n||example.com\n[outbound_block_list]\n||ads.example.com\n",
+    )
+    .unwrap();
+    let acl = crate::acl::AccessControl::load_from_file(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let rules = parse_no_proxy("*");
+    let route = |host: &str| {
+        let dst = Address::from((host, 443));
+        apply_no_proxy(acl_verdict(&acl, &dst), &dst, &rules)
+    };
+    assert!(matches!(route("ads.example.com"), Decision::Deny(_)));
+    assert_eq!(route("www.example.com"), Decision::Direct);
+    assert_eq!(route("example.org"), Decision::Direct);
+    let dst = Address::from(("www.example.com", 443));
+    assert_eq!(acl_verdict(&acl, &dst), Decision::Proxy);
+    assert_eq!(apply_no_proxy(Decision::Proxy, &dst, &[]), Decision::Proxy);
+}
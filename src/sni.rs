@@ -0,0 +1,162 @@
+//! Server Name Indication of the TLS ClientHello sent through a CONNECT tunnel, for `--sni-routing`.
+
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// How long to wait for the client to start talking, protocols where the server speaks first get no SNI
+pub(crate) const SNI_TIMEOUT: Duration = Duration::from_secs(3);
+
+const RECORD_HEADER_LEN: usize = 5;
+const MAX_RECORD_LEN: usize = 16384 + 2048;
+
+/// Read the first TLS record sent by the client. Returns the bytes read, to be replayed to the destination,
+/// and the SNI host name if the record is a ClientHello that carries one.
+pub(crate) async fn read_client_hello<R: AsyncRead + Unpin>(stream: &mut R) -> std::io::Result<(Vec<u8>, Option<String>)> {
+    let mut buf = Vec::with_capacity(1024);
+    match tokio::time::timeout(SNI_TIMEOUT, read_record(stream, &mut buf)).await {
+        Ok(result) => result?,
+        Err(_) => log::debug!("no TLS record within {:?}, {} bytes read", SNI_TIMEOUT, buf.len()),
+    }
+    let sni = server_name(&buf);
+    Ok((buf, sni))
+}
+
+async fn read_record<R: AsyncRead + Unpin>(stream: &mut R, buf: &mut Vec<u8>) -> std::io::Result<()> {
+    let mut chunk = [0; 4096];
+    loop {
+        let wanted = match buf.get(..RECORD_HEADER_LEN) {
+            Some(header) if header[0] != 0x16 => return Ok(()),
+            Some(header) => RECORD_HEADER_LEN + usize::from(u16::from_be_bytes([header[3], header[4]])),
+            None => RECORD_HEADER_LEN,
+        };
+        if buf.len() >= wanted || wanted > MAX_RECORD_LEN {
+            return Ok(());
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// The host name of the `server_name` extension of the ClientHello in the TLS record `record`
+fn server_name(record: &[u8]) -> Option<String> {
+    let mut r = Reader(record);
+    if r.u8()? != 0x16 {
+        return None;
+    }
+    r.skip(2)?;
+    let mut r = Reader(r.block(2)?);
+    // Handshake type ClientHello
+    if r.u8()? != 0x01 {
+        return None;
+    }
+    let mut hello = Reader(r.block(3)?);
+    // Legacy version and random
+    hello.skip(2 + 32)?;
+    hello.block(1)?; // session id
+    hello.block(2)?; // cipher suites
+    hello.block(1)?; // compression methods
+    let mut extensions = Reader(hello.block(2)?);
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let data = extensions.block(2)?;
+        if kind != 0x0000 {
+            continue;
+        }
+        let mut names = Reader(Reader(data).block(2)?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let name = names.block(2)?;
+            if name_type == 0 {
+                return std::str::from_utf8(name).ok().map(|name| name.to_ascii_lowercase());
+            }
+        }
+    }
+    None
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn skip(&mut self, n: usize) -> Option<()> {
+        self.take(n).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    /// A block prefixed by its length on `len_bytes` bytes
+    fn block(&mut self, len_bytes: usize) -> Option<&'a [u8]> {
+        let len = self.take(len_bytes)?.iter().fold(0_usize, |len, b| len << 8 | usize::from(*b));
+        self.take(len)
+    }
+}
+
+#[cfg(test)]
+fn client_hello(host: &str) -> Vec<u8> {
+    let with_len = |len_bytes: usize, body: &[u8]| {
+        let mut out = body.len().to_be_bytes()[8 - len_bytes..].to_vec();
+        out.extend_from_slice(body);
+        out
+    };
+    let mut name = vec![0];
+    name.extend(with_len(2, host.as_bytes()));
+    let mut sni = vec![0, 0];
+    sni.extend(with_len(2, &with_len(2, &name)));
+    let mut extensions = vec![0x00, 0x0b, 0x00, 0x02, 0x01, 0x00];
+    extensions.extend(sni);
+    let mut hello = vec![0x03, 0x03];
+    hello.extend([7; 32]);
+    hello.extend(with_len(1, &[1, 2, 3]));
+    hello.extend(with_len(2, &[0x13, 0x01]));
+    hello.extend(with_len(1, &[0]));
+    hello.extend(with_len(2, &extensions));
+    let mut handshake = vec![0x01];
+    handshake.extend(with_len(3, &hello));
+    let mut record = vec![0x16, 0x03, 0x01];
+    record.extend(with_len(2, &handshake));
+    record
+}
+
+#[test]
+fn test_server_name() {
+    let record = client_hello("Example.COM");
+    assert_eq!(server_name(&record), Some("example.com".to_string()));
+    assert_eq!(server_name(&record[..record.len() - 1]), None);
+    assert_eq!(server_name(b"GET / HTTP/1.1\r\n\r\n"), None);
+}
+
+#[tokio::test]
+async fn test_read_client_hello() {
+    let mut data = client_hello("example.com");
+    let hello_len = data.len();
+    data.extend_from_slice(b"more data");
+    let (mut client, mut server) = tokio::io::duplex(64);
+    tokio::spawn(async move {
+        use tokio::io::AsyncWriteExt;
+        // The record arrives in small pieces
+        for chunk in data.chunks(10) {
+            client.write_all(chunk).await.unwrap();
+        }
+        client.shutdown().await.unwrap();
+    });
+    let (read, sni) = read_client_hello(&mut server).await.unwrap();
+    assert_eq!(sni.as_deref(), Some("example.com"));
+    assert!(read.len() >= hello_len);
+}
@@ -1,160 +1,485 @@
-use crate::{BoxError, Config, Result, CONNECT_TIMEOUT};
+use crate::{
+    config::DenyAction,
+    routing::{self, Decision},
+    BoxError, Config, PortRange, ReplyAddr, Result,
+};
 use socks5_impl::{
-    protocol::{Address, Reply, UdpHeader, UserKey},
+    protocol::{handshake::password_method, Address, AsyncStreamOperation, AuthMethod, Reply, StreamOperation, UdpHeader, UserKey},
     server::{
         auth,
         connection::{associate, connect},
-        AssociatedUdpSocket, ClientConnection, Connect, IncomingConnection, Server, UdpAssociate,
+        AssociatedUdpSocket, AuthExecutor, ClientConnection, Connect, IncomingConnection, Server, UdpAssociate,
     },
 };
-use std::{net::SocketAddr, sync::Arc};
-use tokio::{net::UdpSocket, sync::mpsc::Receiver};
-
-#[cfg(feature = "acl")]
-static ACL_CENTER: std::sync::OnceLock<Option<crate::acl::AccessControl>> = std::sync::OnceLock::new();
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio_util::sync::CancellationToken;
 
 pub(crate) static MAX_UDP_RELAY_PACKET_SIZE: usize = 1500;
 
-pub async fn main_entry<F>(config: &Config, quit: Receiver<()>, callback: Option<F>) -> Result<(), BoxError>
+/// The receive buffer of the datagrams from the remote SOCKS5 server, that of socks5-impl which truncates the
+/// longer ones. A datagram filling it is taken as truncated.
+const UPSTREAM_UDP_BUFFER_SIZE: usize = 1500;
+
+/// The version of the username/password sub-negotiation, RFC 1929
+const SUBNEGOTIATION_VERSION: u8 = 0x01;
+
+pub async fn main_entry<F>(config: &Config, token: CancellationToken, callback: Option<F>) -> Result<(), BoxError>
 where
     F: FnOnce(SocketAddr) + Send + Sync + 'static,
 {
-    #[cfg(feature = "acl")]
-    ACL_CENTER.get_or_init(|| {
-        config
-            .acl_file
-            .as_ref()
-            .and_then(|acl_file| crate::acl::AccessControl::load_from_file(acl_file).ok())
-    });
+    let mut connections = Socks5Connections::bind_with_token(config, token.clone()).await?;
+    if let Some(callback) = callback {
+        callback(connections.local_addr());
+    } else {
+        log::info!("Listening on {}", config.listen_url(connections.local_addr()));
+    }
+    crate::privileges::switch_user(config)?;
+    while let Some(conn) = connections.accept().await {
+        let conn = conn?;
+        tokio::spawn(async move {
+            if let Err(err) = conn.relay().await {
+                log::error!("{err}");
+            }
+        });
+    }
+    if crate::admin::is_draining() {
+        token.cancelled().await;
+    }
+    Ok(())
+}
 
-    let listen_addr = config.listen_addr;
-    let server_addr = config.server_addr;
-    let credentials = config.get_credentials();
-    let s5_auth = config.get_s5_credentials().try_into().ok();
-    match (credentials.username, credentials.password) {
-        (Some(username), Some(password)) => {
-            let auth = Arc::new(auth::UserKeyAuth::new(&username, &password));
-            main_loop(auth, listen_addr, server_addr, s5_auth, quit, callback).await?;
-        }
-        _ => {
-            let auth = Arc::new(auth::NoAuth);
-            main_loop(auth, listen_addr, server_addr, s5_auth, quit, callback).await?;
+/// The SOCKS5 CONNECT requests accepted by a listener bound to `listen_addr`, as a [`futures_core::Stream`].
+///
+/// Every connection has passed the client allow list and the authentication, and its destination has been
+/// rewritten by the `rewrites` rules. UDP ASSOCIATE requests are still relayed by the hub itself and BIND
+/// requests are refused. Dropping the stream closes the listener.
+pub struct Socks5Connections {
+    local_addr: SocketAddr,
+    rx: tokio::sync::mpsc::Receiver<std::io::Result<Socks5Connection>>,
+}
+
+impl Socks5Connections {
+    /// Listen on the `listen_addr` of `config` with its client authentication and routing rules.
+    pub async fn bind(config: &Config) -> Result<Self> {
+        Self::bind_with_token(config, CancellationToken::new()).await
+    }
+
+    pub(crate) async fn bind_with_token(config: &Config, token: CancellationToken) -> Result<Self> {
+        crate::init_run(config)?;
+
+        if config.accept_proxy_protocol {
+            // socks5-impl reads the handshake itself from the accepted socket, before the header could be taken off
+            let err = "--accept-proxy-protocol is only supported by the HTTP listener";
+            return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, err).into());
         }
+        let credentials = config.get_credentials();
+        let listener = crate::bind_listener(config.listen_addr, config)?;
+        let mut config = config.clone();
+        // The actual address, when bound to port 0
+        config.listen_addr = listener.local_addr()?;
+        routing::check_loop(&config, config.listen_addr)?;
+        let config = Arc::new(config);
+        let (tx, rx) = tokio::sync::mpsc::channel(64);
+        let user_key = match (credentials.username, credentials.password) {
+            (Some(username), Some(password)) => Some(auth::UserKeyAuth::new(&username, &password)),
+            _ => None,
+        };
+        let auth = Arc::new(ClientAuth {
+            user_key,
+            config: config.clone(),
+        });
+        let local_addr = spawn_accept_loop(Server::new(listener, auth), config, token, tx)?;
+        Ok(Socks5Connections { local_addr, rx })
     }
 
-    Ok(())
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The next accepted connection, `None` once the listener is closed.
+    /// An error means the listener failed and no more connection follows.
+    pub async fn accept(&mut self) -> Option<std::io::Result<Socks5Connection>> {
+        self.rx.recv().await
+    }
 }
 
-async fn main_loop<S, F>(
-    auth: auth::AuthAdaptor<S>,
-    listen_addr: SocketAddr,
-    server: SocketAddr,
+impl futures_core::Stream for Socks5Connections {
+    type Item = std::io::Result<Socks5Connection>;
+
+    fn poll_next(mut self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+/// A SOCKS5 CONNECT request waiting for its reply.
+pub struct Socks5Connection {
+    connect: Connect<connect::NeedReply>,
+    peer_addr: SocketAddr,
+    destination: Address,
     s5_auth: Option<UserKey>,
-    mut quit: Receiver<()>,
-    callback: Option<F>,
-) -> Result<()>
-where
-    S: Send + Sync + 'static,
-    F: FnOnce(SocketAddr) + Send + Sync + 'static,
-{
-    let listener = Server::bind(listen_addr, auth).await?;
-    if let Some(callback) = callback {
-        callback(listener.local_addr()?);
-    } else {
-        log::info!("Listening on socks5://{}", listener.local_addr()?);
+    config: Arc<Config>,
+    token: CancellationToken,
+    span: crate::span::Span,
+    _guard: crate::admin::ConnectionGuard,
+}
+
+impl Socks5Connection {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
     }
-    loop {
-        tokio::select! {
-            _ = quit.recv() => {
-                log::info!("quit signal received");
-                break;
+
+    pub fn destination(&self) -> &Address {
+        &self.destination
+    }
+
+    /// Send the reply to the client and take over the connection, to relay it with custom logic.
+    pub async fn reply(self, reply: Reply, bind_addr: Address) -> std::io::Result<Connect<connect::Ready>> {
+        self.connect.reply(reply, bind_addr).await
+    }
+
+    /// Relay the connection the way the hub does, following the ACL and the routes of the config.
+    pub async fn relay(self) -> Result<()> {
+        let Socks5Connection {
+            connect,
+            destination,
+            s5_auth,
+            config,
+            token,
+            span,
+            _guard,
+            ..
+        } = self;
+        crate::span::instrument(handle_s5_client_connection(connect, destination, &config, s5_auth, &token), span).await
+    }
+}
+
+/// What is left to do with a client once the authentication is done
+enum Handshake {
+    /// The client was refused
+    Failed,
+    /// The client sent a Tor RESOLVE request, which has been answered
+    Resolved,
+    /// The client request is to be read
+    Request,
+}
+
+/// The client authentication of the listener. It also answers the Tor RESOLVE requests when `tor_resolve`
+/// is set, since socks5-impl parses only the standard commands.
+struct ClientAuth {
+    user_key: Option<auth::UserKeyAuth>,
+    config: Arc<Config>,
+}
+
+#[async_trait::async_trait]
+impl AuthExecutor for ClientAuth {
+    type Output = std::io::Result<Handshake>;
+
+    fn auth_method(&self) -> AuthMethod {
+        match self.user_key {
+            Some(_) => AuthMethod::UserPass,
+            None => AuthMethod::NoAuth,
+        }
+    }
+
+    async fn execute(&self, stream: &mut TcpStream) -> Self::Output {
+        if let Some(user_key) = &self.user_key {
+            // socks5-impl gives up on another sub-negotiation version without telling the client
+            let mut version = [0];
+            if stream.peek(&mut version).await? == 0 {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
             }
-            result = listener.accept() => {
-                let (conn, _) = result?;
-                let s5_auth = s5_auth.clone();
-                tokio::spawn(async move {
-                    if let Err(err) = handle(conn, server, s5_auth).await {
-                        log::error!("{err}");
-                    }
-                });
+            if version[0] != SUBNEGOTIATION_VERSION {
+                log::debug!("username/password sub-negotiation version {:#x} refused", version[0]);
+                let resp = password_method::Response::new(password_method::Status::Failed);
+                resp.write_to_async_stream(stream).await?;
+                return Ok(Handshake::Failed);
+            }
+            if self.config.is_auth_bypassed(stream.peer_addr()?.ip()) {
+                password_method::Request::retrieve_from_async_stream(stream).await?;
+                log::trace!("loopback client, credentials not checked");
+                let resp = password_method::Response::new(password_method::Status::Succeeded);
+                resp.write_to_async_stream(stream).await?;
+            } else if !user_key.execute(stream).await? {
+                return Ok(Handshake::Failed);
             }
         }
+        if self.config.tor_resolve && crate::resolve::is_resolve_request(stream).await? {
+            let s5_auth = self.config.get_s5_credentials().render(stream.peer_addr()?.ip()).try_into().ok();
+            crate::resolve::handle(stream, &self.config, s5_auth)
+                .await
+                .map_err(crate::std_io_error_other)?;
+            return Ok(Handshake::Resolved);
+        }
+        Ok(Handshake::Request)
     }
-    Ok(())
 }
 
-async fn handle<S>(conn: IncomingConnection<S>, server: SocketAddr, s5_auth: Option<UserKey>) -> Result<()>
-where
-    S: Send + Sync + 'static,
-{
-    let (conn, res) = conn.authenticate().await?;
+type ClientHandshake = std::io::Result<Handshake>;
+
+fn spawn_accept_loop(
+    listener: Server<ClientHandshake>,
+    config: Arc<Config>,
+    token: CancellationToken,
+    tx: tokio::sync::mpsc::Sender<std::io::Result<Socks5Connection>>,
+) -> std::io::Result<SocketAddr> {
+    let local_addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = token.cancelled() => {
+                    break;
+                }
+                _ = tx.closed() => {
+                    break;
+                }
+                _ = crate::admin::drained() => {
+                    log::info!("draining, stop accepting new connections");
+                    break;
+                }
+                result = crate::accept::accept_retry(|| listener.accept()) => {
+                    let (conn, incoming) = match result {
+                        Ok(accepted) => accepted,
+                        Err(err) => {
+                            let _ = tx.send(Err(err)).await;
+                            break;
+                        }
+                    };
+                    if !routing::is_client_allowed(&config, incoming.ip()) {
+                        log::debug!("client {} is not allowed, connection closed", incoming);
+                        continue;
+                    }
+                    let config = config.clone();
+                    let token = token.clone();
+                    // A weak sender lets the stream end with the accept loop, even while handshakes are pending
+                    let tx = tx.downgrade();
+                    let span = crate::span::connection(incoming);
+                    tokio::spawn(crate::span::instrument(async move {
+                        let guard = crate::admin::ConnectionGuard::new();
+                        match handle(conn, config, token, guard).await {
+                            Ok(Some(conn)) => {
+                                if let Some(tx) = tx.upgrade() {
+                                    let _ = tx.send(Ok(conn)).await;
+                                }
+                            }
+                            Ok(None) => {}
+                            Err(err) => log::error!("{err}"),
+                        }
+                    }, span));
+                }
+            }
+        }
+    });
+    Ok(local_addr)
+}
+
+/// Authenticate the client and read its request. UDP ASSOCIATE and BIND are served here, a CONNECT is handed back.
+async fn handle(
+    conn: IncomingConnection<ClientHandshake>,
+    config: Arc<Config>,
+    token: CancellationToken,
+    guard: crate::admin::ConnectionGuard,
+) -> Result<Option<Socks5Connection>> {
+    let peer_addr = conn.peer_addr()?;
+    let s5_auth = config.get_s5_credentials().render(peer_addr.ip()).try_into().ok();
+
+    let (conn, res) = match conn.authenticate().await {
+        Ok(authenticated) => authenticated,
+        // socks5-impl has replied with no acceptable methods, a client error rather than one of the hub
+        Err(err) if err.kind() == std::io::ErrorKind::Unsupported => {
+            log::info!("client offered no acceptable authentication method, connection closed");
+            return Ok(None);
+        }
+        Err(err) => return Err(err.into()),
+    };
 
-    use as_any::AsAny;
-    if let Some(res) = res.as_any().downcast_ref::<std::io::Result<bool>>() {
-        let res = *res.as_ref().map_err(|err| err.to_string())?;
-        if !res {
+    match res? {
+        Handshake::Failed => {
             log::info!("authentication failed");
-            return Ok(());
+            return Ok(None);
         }
+        Handshake::Resolved => return Ok(None),
+        Handshake::Request => {}
     }
 
     match conn.wait_request().await? {
-        ClientConnection::UdpAssociate(associate, _) => {
-            handle_s5_upd_associate(associate, server, s5_auth).await?;
+        ClientConnection::UdpAssociate(associate, _) if routing::upstreams_down(&config) => {
+            log::info!("all the upstreams are down, UDP associate refused");
+            let mut conn = associate.reply(Reply::NetworkUnreachable, Address::unspecified()).await?;
+            conn.shutdown().await?;
+        }
+        ClientConnection::UdpAssociate(associate, client_addr) => {
+            handle_s5_upd_associate(associate, client_addr, &config, s5_auth, &token).await?;
         }
         ClientConnection::Bind(bind, _) => {
             let mut conn = bind.reply(Reply::CommandNotSupported, Address::unspecified()).await?;
             conn.shutdown().await?;
         }
         ClientConnection::Connect(connect, dst) => {
-            handle_s5_client_connection(connect, dst, server, s5_auth).await?;
+            let destination = routing::rewrite(&config, dst);
+            crate::span::record_destination(&destination);
+            return Ok(Some(Socks5Connection {
+                connect,
+                peer_addr,
+                destination,
+                s5_auth,
+                config,
+                token,
+                span: crate::span::Span::current(),
+                _guard: guard,
+            }));
         }
     }
 
-    Ok(())
+    Ok(None)
 }
 
 async fn handle_s5_client_connection(
     connect: Connect<connect::NeedReply>,
     dst: Address,
-    server: SocketAddr,
+    config: &Config,
     s5_auth: Option<UserKey>,
+    token: &CancellationToken,
 ) -> Result<()> {
-    #[cfg(feature = "acl")]
-    {
-        let mut must_proxied = true;
-        if let Some(Some(acl)) = ACL_CENTER.get() {
-            must_proxied = acl.check_host_in_proxy_list(&dst.domain()).unwrap_or_default();
+    if let Address::DomainAddress(host, _) = &dst {
+        if let Some(path) = host.strip_prefix("unix:").filter(|_| config.unix_connect) {
+            return handle_unix_connection(connect, path, token).await;
         }
-        if !must_proxied {
+    }
+
+    let server = routing::upstream(config, &dst);
+    match routing::decide(&dst) {
+        Decision::Deny(rule) => {
+            log::info!("destination {} denied by rule {}", dst, rule);
+            if config.deny_action == DenyAction::Reply {
+                let mut conn = connect.reply(Reply::ConnectionNotAllowed, Address::unspecified()).await?;
+                conn.shutdown().await?;
+            }
+            return Ok(());
+        }
+        Decision::Direct => {
             log::debug!("connect to destination address {:?} without proxy", dst);
             use std::net::ToSocketAddrs;
             let addr = dst.to_socket_addrs()?.next().ok_or(crate::std_io_error_other("no address found"))?;
+            if routing::is_own_listener(config.listen_addr, addr) {
+                let mut conn = connect.reply(Reply::ConnectionNotAllowed, Address::unspecified()).await?;
+                conn.shutdown().await?;
+                return Err(routing::loop_error(&dst).into());
+            }
             let mut server = tokio::net::TcpStream::connect(addr).await?;
-            let mut conn = connect.reply(Reply::Succeeded, Address::unspecified()).await?;
-            log::trace!("{} -> {}", conn.peer_addr()?, dst);
-            tokio::io::copy_bidirectional(&mut server, &mut conn).await?;
+            let bind_addr = reply_addr(config, &connect, || server.local_addr().map(Address::from))?;
+            let mut conn = connect.reply(Reply::Succeeded, bind_addr).await?;
+            let peer_addr = conn.peer_addr()?;
+            log::trace!("{} -> {} via direct", peer_addr, dst);
+            let (from_client, from_server) = crate::relay(&mut conn, &mut server, token).await?;
+            log::debug!(
+                "{} -> {} via direct: client wrote {} and received {}",
+                peer_addr,
+                dst,
+                crate::format_bytes(from_client),
+                crate::format_bytes(from_server)
+            );
             return Ok(());
         }
+        Decision::Proxy if routing::upstreams_down(config) => {
+            log::info!("all the upstreams are down, connection to {} refused", dst);
+            let mut conn = connect.reply(Reply::NetworkUnreachable, Address::unspecified()).await?;
+            conn.shutdown().await?;
+            return Ok(());
+        }
+        Decision::Proxy => {}
     }
 
-    let mut stream = crate::create_s5_connect(server, CONNECT_TIMEOUT, &dst, s5_auth).await?;
-    let mut conn = connect.reply(Reply::Succeeded, Address::unspecified()).await?;
-    log::trace!("{} -> {}", conn.peer_addr()?, dst);
+    if routing::loops_through(config.listen_addr, server, &dst) {
+        let mut conn = connect.reply(Reply::ConnectionNotAllowed, Address::unspecified()).await?;
+        conn.shutdown().await?;
+        return Err(routing::loop_error(&dst).into());
+    }
+    let (mut stream, bound) = crate::create_s5_connect(server, routing::connect_timeout(&dst), &dst, s5_auth).await?;
+    let bind_addr = reply_addr(config, &connect, || Ok(bound))?;
+    let mut conn = connect.reply(Reply::Succeeded, bind_addr).await?;
+    let peer_addr = conn.peer_addr()?;
+    log::trace!("{} -> {} via socks5://{}", peer_addr, dst, server);
+
+    let _probe = crate::latency::probe(stream.get_ref(), &dst);
+    let (from_client, from_server) = crate::relay(&mut conn, &mut stream, token).await?;
+    log::debug!(
+        "{} -> {} via socks5://{}: client wrote {} and received {}",
+        peer_addr,
+        dst,
+        server,
+        crate::format_bytes(from_client),
+        crate::format_bytes(from_server)
+    );
+
+    Ok(())
+}
 
-    tokio::io::copy_bidirectional(&mut stream, &mut conn).await?;
+/// The BND.ADDR of the successful reply to `connect` by the `connect_reply_addr` of `config`, `upstream` is the
+/// address reported for the connection to the destination
+fn reply_addr<S: Default>(
+    config: &Config,
+    connect: &Connect<S>,
+    upstream: impl FnOnce() -> std::io::Result<Address>,
+) -> std::io::Result<Address> {
+    Ok(match config.connect_reply_addr {
+        ReplyAddr::Unspecified => Address::unspecified(),
+        ReplyAddr::Local => Address::from(connect.local_addr()?),
+        ReplyAddr::Upstream => upstream()?,
+        ReplyAddr::Fixed(addr) => Address::from(addr),
+    })
+}
 
+/// Relay a CONNECT request for `unix:/path` to the local Unix domain socket at `path`
+#[cfg(unix)]
+async fn handle_unix_connection(connect: Connect<connect::NeedReply>, path: &str, token: &CancellationToken) -> Result<()> {
+    let mut stream = match tokio::net::UnixStream::connect(path).await {
+        Ok(stream) => stream,
+        Err(err) => {
+            let mut conn = connect.reply(Reply::ConnectionRefused, Address::unspecified()).await?;
+            conn.shutdown().await?;
+            return Err(format!("connect to unix:{} failed: {}", path, err).into());
+        }
+    };
+    let mut conn = connect.reply(Reply::Succeeded, Address::unspecified()).await?;
+    let peer_addr = conn.peer_addr()?;
+    let (from_client, from_server) = crate::relay(&mut conn, &mut stream, token).await?;
+    log::debug!(
+        "{} -> unix:{}: client wrote {} and received {}",
+        peer_addr,
+        path,
+        crate::format_bytes(from_client),
+        crate::format_bytes(from_server)
+    );
     Ok(())
 }
 
+#[cfg(not(unix))]
+async fn handle_unix_connection(connect: Connect<connect::NeedReply>, path: &str, _token: &CancellationToken) -> Result<()> {
+    let mut conn = connect.reply(Reply::AddressTypeNotSupported, Address::unspecified()).await?;
+    conn.shutdown().await?;
+    Err(format!("unix:{} refused, Unix domain sockets are not supported on this platform", path).into())
+}
+
 pub(crate) async fn handle_s5_upd_associate(
     associate: UdpAssociate<associate::NeedReply>,
-    server: SocketAddr,
+    client_addr: Address,
+    config: &Config,
     s5_auth: Option<UserKey>,
+    token: &CancellationToken,
 ) -> Result<()> {
-    // listen on a random port
-    let listen_ip = associate.local_addr()?.ip();
-    let udp_listener = UdpSocket::bind(SocketAddr::from((listen_ip, 0))).await;
+    let server = config.server_addr;
+    let udp_recv_timeout = Duration::from_secs(config.udp_recv_timeout);
+
+    let listen_ip = match config.udp_bind_addr {
+        Some(ip) => ip,
+        None => associate.local_addr()?.ip(),
+    };
+    let udp_listener = bind_udp_relay(listen_ip, config.udp_port_range).await;
 
     let result = udp_listener.and_then(|socket| socket.local_addr().map(|addr| (socket, addr)));
     if let Err(err) = result {
@@ -165,54 +490,90 @@ pub(crate) async fn handle_s5_upd_associate(
     let (listen_udp, listen_addr) = result?;
     log::info!("[UDP] {listen_addr} listen on");
 
-    let s5_listen_addr = Address::from(listen_addr);
+    // TODO: UserKey is always None, this is a bug
+    let s5_auth = crate::outbound::credentials(server, s5_auth);
+    let s5_udp_client = match create_udp_client(server, s5_auth, config.udp_upstream_retries).await {
+        Ok(client) => client,
+        Err(err) => {
+            let mut conn = associate.reply(udp_upstream_failure_reply(&err), Address::unspecified()).await?;
+            conn.shutdown().await?;
+            return Err(format!("[UDP] upstream socks5://{} failed: {}", server, err).into());
+        }
+    };
+
+    let s5_listen_addr = udp_advertised_addr(config, listen_addr, associate.local_addr()?.ip());
     let mut reply_listener = associate.reply(Reply::Succeeded, s5_listen_addr).await?;
 
-    let buf_size = MAX_UDP_RELAY_PACKET_SIZE - UdpHeader::max_serialized_len();
-    let listen_udp = Arc::new(AssociatedUdpSocket::from((listen_udp, buf_size)));
+    // The datagrams of the client longer than this are dropped. One more byte is read to tell them apart, the
+    // socket truncates what doesn't fit the buffer.
+    let max_datagram_len = MAX_UDP_RELAY_PACKET_SIZE - UdpHeader::max_serialized_len();
+    let listen_udp = Arc::new(AssociatedUdpSocket::from((listen_udp, max_datagram_len + 1)));
 
     let incoming_addr = std::sync::OnceLock::new();
 
-    // TODO: UserKey is always None, this is a bug
-    let s5_udp_client = socks5_impl::client::create_udp_client(server, s5_auth).await?;
-
     let res = loop {
         tokio::select! {
             res = async {
-                let buf_size = MAX_UDP_RELAY_PACKET_SIZE - UdpHeader::max_serialized_len();
-                listen_udp.set_max_packet_size(buf_size);
-
                 let (pkt, frag, dst_addr, src_addr) = listen_udp.recv_from().await?;
                 if frag != 0 {
                     return Err("[UDP] packet fragment is not supported".into());
                 }
+                if UdpHeader::new(frag, dst_addr.clone()).len() + pkt.len() > max_datagram_len {
+                    log::warn!("[UDP] {src_addr} -> {dst_addr} datagram over {max_datagram_len} bytes dropped");
+                    return Ok(());
+                }
+
+                if !is_expected_udp_source(&client_addr, &src_addr) {
+                    log::debug!("[UDP] {src_addr} -> {dst_addr} dropped, client declared {client_addr}");
+                    return Ok(());
+                }
 
                 let _a = incoming_addr.get_or_init(|| src_addr);
 
                 log::trace!("[UDP] {src_addr} -> {dst_addr} incoming packet size {}", pkt.len());
-                let _ = s5_udp_client.send_to(&pkt, dst_addr).await?;
+                if let Err(err) = s5_udp_client.send_to(&pkt, &dst_addr).await {
+                    log::debug!("[UDP] {src_addr} -> {dst_addr} send failed: {err}");
+                }
                 Ok::<_, BoxError>(())
             } => {
-                if res.is_err() {
-                    break res;
+                if let Err(err) = res {
+                    if !is_transient_udp_error(err.as_ref()) {
+                        break Err(err);
+                    }
+                    log::debug!("[UDP] {listen_addr} datagram dropped: {err}");
                 }
             },
             res = async {
                 let mut buf = vec![0u8; MAX_UDP_RELAY_PACKET_SIZE];
-                let (len, remote_addr) = s5_udp_client.recv_from(CONNECT_TIMEOUT, &mut buf).await?;
+                let (len, remote_addr) = s5_udp_client.recv_from(udp_recv_timeout, &mut buf).await?;
                 let incoming_addr = *incoming_addr.get().ok_or("incoming address not set")?;
+                if UdpHeader::new(0, remote_addr.clone()).len() + len >= UPSTREAM_UDP_BUFFER_SIZE {
+                    log::warn!(
+                        "[UDP] {incoming_addr} <- {remote_addr} datagram of {UPSTREAM_UDP_BUFFER_SIZE} bytes or more dropped, it may be truncated"
+                    );
+                    return Ok(());
+                }
                 log::trace!("[UDP] {incoming_addr} <- {remote_addr} feedback to incoming");
-                listen_udp.send_to(&buf[..len], 0, remote_addr, incoming_addr).await?;
+                if let Err(err) = listen_udp.send_to(&buf[..len], 0, remote_addr.clone(), incoming_addr).await {
+                    log::debug!("[UDP] {incoming_addr} <- {remote_addr} send failed: {err}");
+                }
                 Ok::<_, BoxError>(())
             } => {
-                if res.is_err() {
-                    break res;
+                if let Err(err) = res {
+                    if !is_transient_udp_error(err.as_ref()) {
+                        break Err(err);
+                    }
+                    log::debug!("[UDP] {listen_addr} datagram dropped: {err}");
                 }
             },
             _ = reply_listener.wait_until_closed() => {
                 log::trace!("[UDP] {} listener closed", listen_addr);
                 break Ok::<_, BoxError>(());
             },
+            _ = token.cancelled() => {
+                log::trace!("[UDP] {} relay cancelled by shutdown", listen_addr);
+                break Ok::<_, BoxError>(());
+            },
         };
     };
 
@@ -220,3 +581,197 @@ pub(crate) async fn handle_s5_upd_associate(
 
     res
 }
+
+/// The address of the UDP relay socket bound to `listen_addr` sent in the UDP ASSOCIATE reply, by the
+/// `udp_advertise_addr` and `udp_advertise_port` of `config`. An unspecified `listen_addr` is advertised with
+/// `local_ip`, the address the client reached the hub at.
+fn udp_advertised_addr(config: &Config, listen_addr: SocketAddr, local_ip: IpAddr) -> Address {
+    let ip = match config.udp_advertise_addr {
+        Some(ip) => ip,
+        None if listen_addr.ip().is_unspecified() => local_ip,
+        None => listen_addr.ip(),
+    };
+    Address::from((ip, config.udp_advertise_port.unwrap_or(listen_addr.port())))
+}
+
+/// Bind a UDP relay socket to a free port of `port_range`, starting at a random port of the range,
+/// or to a random port chosen by the system if no range is set.
+async fn bind_udp_relay(ip: IpAddr, port_range: Option<PortRange>) -> std::io::Result<UdpSocket> {
+    let Some(range) = port_range else {
+        return UdpSocket::bind(SocketAddr::from((ip, 0))).await;
+    };
+    use std::hash::{BuildHasher, Hasher};
+    let offset = std::collections::hash_map::RandomState::new().build_hasher().finish() as usize;
+    let count = (range.end - range.start) as usize + 1;
+    for i in 0..count {
+        let port = range.start + ((offset + i) % count) as u16;
+        match UdpSocket::bind(SocketAddr::from((ip, port))).await {
+            Err(err) if err.kind() == std::io::ErrorKind::AddrInUse => continue,
+            res => return res,
+        }
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::AddrInUse,
+        format!("no free UDP port in range {}", range),
+    ))
+}
+
+/// Create the UDP client of the remote SOCKS5 server `server`, with up to `retries` more attempts after a
+/// failure to reach it, the delay between them doubling from 100ms. A failure reply of the server is final.
+async fn create_udp_client(
+    server: SocketAddr,
+    auth: Option<UserKey>,
+    retries: u32,
+) -> std::io::Result<socks5_impl::client::SocksUdpClient> {
+    let mut delay = Duration::from_millis(100);
+    let mut attempt = 0;
+    loop {
+        let err = match tokio::time::timeout(crate::CONNECT_TIMEOUT, socks5_impl::client::create_udp_client(server, auth.clone())).await {
+            Ok(Ok(client)) => return Ok(client),
+            Ok(Err(socks5_impl::Error::Io(err))) => err,
+            Ok(Err(err)) => return Err(err.into()),
+            Err(elapsed) => elapsed.into(),
+        };
+        if attempt == retries {
+            return Err(err);
+        }
+        attempt += 1;
+        log::debug!(
+            "[UDP] upstream socks5://{} failed: {}, retry {} of {} in {:?}",
+            server,
+            err,
+            attempt,
+            retries,
+            delay
+        );
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+    }
+}
+
+/// The reply to the client when the UDP client of the remote SOCKS5 server can't be created
+fn udp_upstream_failure_reply(err: &std::io::Error) -> Reply {
+    match err.kind() {
+        std::io::ErrorKind::ConnectionRefused => Reply::ConnectionRefused,
+        std::io::ErrorKind::TimedOut => Reply::TtlExpired,
+        _ => Reply::GeneralFailure,
+    }
+}
+
+/// Whether a receive error of the UDP relay loop only loses the datagram at hand, such as a malformed packet
+/// or an ICMP error reported on the socket, send errors always do. The association is kept alive after such
+/// errors, any other socket error and the idle timeout end it.
+fn is_transient_udp_error(err: &(dyn std::error::Error + 'static)) -> bool {
+    let io_err = match err.downcast_ref::<socks5_impl::Error>() {
+        Some(socks5_impl::Error::Io(io_err)) => io_err,
+        Some(_) => return true,
+        None => match err.downcast_ref::<std::io::Error>() {
+            Some(io_err) => io_err,
+            None => return true,
+        },
+    };
+    use std::io::ErrorKind::*;
+    matches!(
+        io_err.kind(),
+        ConnectionRefused | ConnectionReset | InvalidData | InvalidInput | Interrupted | WouldBlock
+    )
+}
+
+/// Check the source of an incoming datagram against the address the client declared in the ASSOCIATE request.
+/// An unspecified IP or a zero port in the declared address matches anything.
+fn is_expected_udp_source(client_addr: &Address, src_addr: &SocketAddr) -> bool {
+    match client_addr {
+        Address::SocketAddress(addr) => {
+            (addr.ip().is_unspecified() || addr.ip() == src_addr.ip()) && (addr.port() == 0 || addr.port() == src_addr.port())
+        }
+        Address::DomainAddress(..) => true,
+    }
+}
+
+#[tokio::test]
+async fn test_no_acceptable_auth_method() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let mut config = Config::default();
+    config.listen_addr("127.0.0.1:0".parse().unwrap());
+    let no_auth = Socks5Connections::bind(&config).await.unwrap();
+    config.username("user").password("pass");
+    let user_pass = Socks5Connections::bind(&config).await.unwrap();
+    for listener in [&no_auth, &user_pass] {
+        let mut client = TcpStream::connect(listener.local_addr()).await.unwrap();
+        // Version 5, one method, GSSAPI
+        client.write_all(&[0x05, 0x01, 0x01]).await.unwrap();
+        let mut reply = [0; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, 0xFF]);
+        let read = tokio::time::timeout(Duration::from_secs(5), client.read(&mut [0; 16])).await;
+        assert_eq!(read.unwrap().unwrap(), 0);
+    }
+}
+
+#[tokio::test]
+async fn test_bad_subnegotiation_version() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let mut config = Config::default();
+    config.listen_addr("127.0.0.1:0".parse().unwrap()).username("user").password("pass");
+    let listener = Socks5Connections::bind(&config).await.unwrap();
+    let mut client = TcpStream::connect(listener.local_addr()).await.unwrap();
+    client.write_all(&[0x05, 0x01, 0x02]).await.unwrap();
+    let mut reply = [0; 2];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply, [0x05, 0x02]);
+    // The right credentials behind the version of the SOCKS protocol itself
+    client.write_all(b"\x05\x04user\x04pass").await.unwrap();
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[0], SUBNEGOTIATION_VERSION);
+    assert_ne!(reply[1], 0x00);
+    // The connection is closed, reset as the server didn't read the rest of the sub-negotiation
+    let read = tokio::time::timeout(Duration::from_secs(5), client.read(&mut [0; 16])).await;
+    match read.unwrap() {
+        Ok(n) => assert_eq!(n, 0),
+        Err(err) => assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset),
+    }
+}
+
+#[tokio::test]
+async fn test_no_auth_bypass_local() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let mut config = Config::default();
+    config.listen_addr("127.0.0.1:0".parse().unwrap()).username("user").password("pass");
+    let checked = Socks5Connections::bind(&config).await.unwrap();
+    config.no_auth_bypass_local(true);
+    let bypassed = Socks5Connections::bind(&config).await.unwrap();
+    for (listener, status) in [(&checked, None), (&bypassed, Some(0x00))] {
+        let mut client = TcpStream::connect(listener.local_addr()).await.unwrap();
+        client.write_all(&[0x05, 0x01, 0x02]).await.unwrap();
+        let mut reply = [0; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, 0x02]);
+        client.write_all(b"\x01\x04user\x05wrong").await.unwrap();
+        let read = client.read_exact(&mut reply).await;
+        match status {
+            Some(status) => assert_eq!(reply, [0x01, status]),
+            None => assert!(read.is_err() || reply[1] != 0x00),
+        }
+    }
+}
+
+#[test]
+fn test_udp_advertised_addr() {
+    let listen: SocketAddr = "127.0.0.1:1080".parse().unwrap();
+    let mut config = Config::new(listen, "127.0.0.1:1081".parse().unwrap());
+    let local_ip: IpAddr = "10.0.0.2".parse().unwrap();
+    let relay: SocketAddr = "10.0.0.2:40000".parse().unwrap();
+    assert_eq!(udp_advertised_addr(&config, relay, local_ip), Address::from(relay));
+    let any: SocketAddr = "0.0.0.0:40000".parse().unwrap();
+    assert_eq!(udp_advertised_addr(&config, any, local_ip), Address::from(relay));
+    config.udp_advertise_addr("203.0.113.1".parse().unwrap());
+    assert_eq!(
+        udp_advertised_addr(&config, any, local_ip),
+        Address::from(("203.0.113.1".parse::<IpAddr>().unwrap(), 40000))
+    );
+    config.udp_advertise_port(5000);
+    assert_eq!(
+        udp_advertised_addr(&config, any, local_ip),
+        Address::from(("203.0.113.1".parse::<IpAddr>().unwrap(), 5000))
+    );
+}
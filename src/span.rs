@@ -0,0 +1,79 @@
+//! Per-connection `tracing` spans, with the `tracing` feature.
+//!
+//! A client connection runs inside a `connection` span with its `peer` address, and the `destination` once
+//! the request is read. Each HTTP request of a connection also gets its own `request` span. The hub still logs
+//! through `log`, install a `tracing_log::LogTracer` to have those records attached to the current span.
+//! Without the feature the spans are no-ops.
+
+use socks5_impl::protocol::Address;
+use std::{future::Future, net::SocketAddr};
+
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::Span;
+
+#[cfg(not(feature = "tracing"))]
+#[derive(Debug, Clone)]
+pub(crate) struct Span;
+
+#[cfg(not(feature = "tracing"))]
+impl Span {
+    pub(crate) fn current() -> Self {
+        Span
+    }
+}
+
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn connection(peer: SocketAddr) -> Span {
+    #[cfg(feature = "tracing")]
+    {
+        tracing::info_span!("connection", peer = %peer, destination = tracing::field::Empty)
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        Span
+    }
+}
+
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn request(method: &hyper::Method) -> Span {
+    #[cfg(feature = "tracing")]
+    {
+        tracing::info_span!("request", method = %method, destination = tracing::field::Empty)
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        Span
+    }
+}
+
+/// Run `fut` inside `span`.
+pub(crate) fn instrument<F: Future>(fut: F, span: Span) -> impl Future<Output = F::Output> {
+    #[cfg(feature = "tracing")]
+    {
+        tracing::Instrument::instrument(fut, span)
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = span;
+        fut
+    }
+}
+
+/// The ID of the current span, as known to the tracing backend, `None` outside of a span or without the feature
+pub(crate) fn current_id() -> Option<u64> {
+    #[cfg(feature = "tracing")]
+    {
+        tracing::Span::current().id().map(|id| id.into_u64())
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        None
+    }
+}
+
+/// Record the destination of the connection or request of the current span.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn record_destination(dst: &Address) {
+    #[cfg(feature = "tracing")]
+    tracing::Span::current().record("destination", tracing::field::display(dst));
+}
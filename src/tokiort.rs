@@ -75,6 +75,12 @@ impl TokioSleep {
 }
 
 pin_project! {
+    /// An adapter between the tokio and hyper IO traits.
+    ///
+    /// `TokioIo<T>` implements hyper's `Read`/`Write` when `T` implements tokio's `AsyncRead`/`AsyncWrite`,
+    /// and implements tokio's `AsyncRead`/`AsyncWrite` when `T` implements hyper's `Read`/`Write`.
+    /// So it can wrap a `tokio::net::TcpStream` to be served by hyper,
+    /// or wrap a `hyper::upgrade::Upgraded` to be used with `tokio::io` utilities.
     #[derive(Debug)]
     pub struct TokioIo<T> {
         #[pin]
@@ -83,10 +89,12 @@ pin_project! {
 }
 
 impl<T> TokioIo<T> {
+    /// Wrap a type implementing the tokio or hyper IO traits.
     pub fn new(inner: T) -> Self {
         Self { inner }
     }
 
+    /// Consume the adapter and return the wrapped value.
     pub fn inner(self) -> T {
         self.inner
     }